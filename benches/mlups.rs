@@ -0,0 +1,34 @@
+use lbm_clean::*;
+use nalgebra::{matrix, vector};
+use std::time::Instant;
+
+/// Million lattice updates per second of `moments` plus `collision`, and of whole
+/// steps, on a periodic D3Q27 box with the scalar and the SIMD kernels
+fn main() {
+    let steps = 20;
+    for simd in [false, true] {
+        let mut solver = Solver::<D3Q27>::new(matrix![0, 63; 0, 63; 0, 63], 1.6, 1.0, 0.0);
+        for axis in 0..3 {
+            solver.set_periodic(axis, true);
+        }
+        solver.set_body_force(BodyForce::Uniform(vector![1e-6, 0.0, 0.0]));
+        solver.set_simd(simd);
+        solver.equilibrium_init();
+        solver.moments();
+        let nodes = solver.density().size() as f64;
+
+        let start = Instant::now();
+        for _ in 0..steps {
+            solver.moments();
+            solver.collision();
+        }
+        let kernels = nodes * steps as f64 / start.elapsed().as_secs_f64() / 1e6;
+
+        let start = Instant::now();
+        for _ in 0..steps {
+            solver.step();
+        }
+        let step = nodes * steps as f64 / start.elapsed().as_secs_f64() / 1e6;
+        println!("simd: {:5}, moments + collision: {:8.2} MLUPS, step: {:8.2} MLUPS", simd, kernels, step);
+    }
+}
@@ -1,83 +1,164 @@
 use crate::*;
 
+/// Order of the populations in `Array4D::buffer`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Array of structs, the populations of a node are contiguous
+    #[default]
+    Aos,
+
+    /// Struct of arrays, each q is a contiguous 3D field
+    Soa,
+}
+
 /// x, y, z, q
-pub struct Array4D {
+pub struct Array4D<T = f32> {
     dimensions: AABB<4>,
     size: usize,
-    pub buffer: Vec<f32>,
+    layout: Layout,
+    pub buffer: Vec<T>,
 }
 
-impl Array4D {
+impl<T: NumTrait> Array4D<T> {
     pub fn new(dimensions: AABB<4>) -> Self {
+        Self::with_layout(dimensions, Layout::Aos)
+    }
+
+    pub fn with_layout(dimensions: AABB<4>, layout: Layout) -> Self {
         let size = box_buffer_size(&dimensions);
 
         Array4D {
             dimensions,
             size,
-            buffer: vec![0.0; size],
+            layout,
+            buffer: vec![T::zero(); size],
         }
     }
 
-    pub fn get(&self, coord: &Coord<4>) -> f32 {
-        let index = coord_to_linear_in_box(coord, &self.dimensions);
-        self.buffer[index]
+    pub fn dimensions(&self) -> &AABB<4> {
+        &self.dimensions
     }
 
-    pub fn get_q(&self, grid_coord: &Coord<3>, q: i32) -> f32 {
-        #[allow(clippy::toplevel_ref_arg)]
-        let coord = nalgebra::stack![grid_coord; nalgebra::vector![q]];
-        let index = coord_to_linear_in_box(&coord, &self.dimensions);
-        self.buffer[index]
+    /// x, y, z
+    pub fn grid_dimensions(&self) -> AABB<3> {
+        self.dimensions.fixed_rows::<3>(0).into_owned()
     }
 
-    pub fn set(&mut self, coord: Coord<4>, value: f32) {
-        let index = coord_to_linear_in_box(&coord, &self.dimensions);
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn q_count(&self) -> usize {
+        (self.dimensions[(3, 1)] - self.dimensions[(3, 0)] + 1) as usize
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Reorder `buffer` into `layout`
+    pub fn set_layout(&mut self, layout: Layout) {
+        if layout == self.layout {
+            return;
+        }
+        let mut reordered = Array4D::with_layout(self.dimensions, layout);
+        for node in 0..self.size / self.q_count() {
+            for q in 0..self.q_count() {
+                let index = reordered.buffer_index(node, q);
+                reordered.buffer[index] = self.buffer[self.buffer_index(node, q)];
+            }
+        }
+        *self = reordered;
+    }
+
+    /// Index into `buffer` of population `q` of the node with linear index `node`
+    pub fn buffer_index(&self, node: usize, q: usize) -> usize {
+        match self.layout {
+            Layout::Aos => node * self.q_count() + q,
+            Layout::Soa => q * (self.size / self.q_count()) + node,
+        }
+    }
+
+    fn index(&self, grid_coord: &Coord<3>, q: i32) -> usize {
+        debug_assert!(q >= self.dimensions[(3, 0)] && q <= self.dimensions[(3, 1)]);
+        let node = coord_to_linear_in_box(grid_coord, &self.grid_dimensions());
+        self.buffer_index(node, (q - self.dimensions[(3, 0)]) as usize)
+    }
+
+    pub fn get(&self, coord: &Coord<4>) -> T {
+        self.buffer[self.index(&coord.fixed_rows::<3>(0).into_owned(), coord[3])]
+    }
+
+    pub fn get_q(&self, grid_coord: &Coord<3>, q: i32) -> T {
+        self.buffer[self.index(grid_coord, q)]
+    }
+
+    pub fn set(&mut self, coord: Coord<4>, value: T) {
+        let index = self.index(&coord.fixed_rows::<3>(0).into_owned(), coord[3]);
         self.buffer[index] = value;
     }
 
-    pub fn set_q(&mut self, grid_coord: &Coord<3>, q: i32, value: f32) {
-        #[allow(clippy::toplevel_ref_arg)]
-        let coord = nalgebra::stack![grid_coord; nalgebra::vector![q]];
-        let index = coord_to_linear_in_box(&coord, &self.dimensions);
+    pub fn set_q(&mut self, grid_coord: &Coord<3>, q: i32, value: T) {
+        let index = self.index(grid_coord, q);
         self.buffer[index] = value;
     }
 }
 
-pub struct Array3D {
+/// Empty array, a placeholder for `std::mem::take`
+impl<T> Default for Array4D<T> {
+    fn default() -> Self {
+        Array4D {
+            dimensions: AABB::zeros(),
+            size: 0,
+            layout: Layout::Aos,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+pub struct Array3D<T = f32> {
     dimensions: AABB<3>,
     size: usize,
-    buffer: Vec<f32>,
+    pub buffer: Vec<T>,
 }
 
-impl Array3D {
+impl<T: NumTrait> Array3D<T> {
     pub fn new(dimensions: AABB<3>) -> Self {
         let size = box_buffer_size(&dimensions);
 
         Array3D {
             dimensions,
             size,
-            buffer: vec![0.0; size],
+            buffer: vec![T::zero(); size],
         }
     }
 
-    pub fn get(&self, coord: &Coord<3>) -> f32 {
+    pub fn dimensions(&self) -> &AABB<3> {
+        &self.dimensions
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&self, coord: &Coord<3>) -> T {
         let index = coord_to_linear_in_box(coord, &self.dimensions);
         self.buffer[index]
     }
 
-    pub fn set(&mut self, coord: &Coord<3>, value: f32) {
-        let index = coord_to_linear_in_box(&coord, &self.dimensions);
+    pub fn set(&mut self, coord: &Coord<3>, value: T) {
+        let index = coord_to_linear_in_box(coord, &self.dimensions);
         self.buffer[index] = value;
     }
 }
 
-pub struct VelArray {
+pub struct VelArray<T = f32> {
     dimensions: AABB<3>,
     size: usize,
-    buffer: Vec<Vec3>,
+    pub buffer: Vec<Vec3<T>>,
 }
 
-impl VelArray {
+impl<T: NumTrait> VelArray<T> {
     pub fn new(dimensions: AABB<3>) -> Self {
         let size = box_buffer_size(&dimensions);
 
@@ -88,13 +169,152 @@ impl VelArray {
         }
     }
 
-    pub fn get(&self, coord: &Coord<3>) -> Vec3 {
+    pub fn dimensions(&self) -> &AABB<3> {
+        &self.dimensions
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&self, coord: &Coord<3>) -> Vec3<T> {
         let index = coord_to_linear_in_box(coord, &self.dimensions);
         self.buffer[index]
     }
 
-    pub fn set(&mut self, coord: &Coord<3>, value: Vec3) {
-        let index = coord_to_linear_in_box(&coord, &self.dimensions);
+    pub fn set(&mut self, coord: &Coord<3>, value: Vec3<T>) {
+        let index = coord_to_linear_in_box(coord, &self.dimensions);
         self.buffer[index] = value;
     }
 }
+
+/// Empty array, a placeholder for `std::mem::take`
+impl<T> Default for VelArray<T> {
+    fn default() -> Self {
+        VelArray {
+            dimensions: AABB::zeros(),
+            size: 0,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Per node `u16` flags, see `BoundaryRegistry::resolve`
+pub struct FlagArray {
+    dimensions: AABB<3>,
+    size: usize,
+    buffer: Vec<u16>,
+}
+
+impl FlagArray {
+    pub fn new(dimensions: AABB<3>) -> Self {
+        let size = box_buffer_size(&dimensions);
+
+        FlagArray {
+            dimensions,
+            size,
+            buffer: vec![0; size],
+        }
+    }
+
+    pub fn dimensions(&self) -> &AABB<3> {
+        &self.dimensions
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&self, coord: &Coord<3>) -> u16 {
+        let index = coord_to_linear_in_box(coord, &self.dimensions);
+        self.buffer[index]
+    }
+
+    pub fn set(&mut self, coord: &Coord<3>, value: u16) {
+        let index = coord_to_linear_in_box(coord, &self.dimensions);
+        self.buffer[index] = value;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NodeType {
+    Fluid = 0,
+
+    /// Carries no populations, fluid neighbors bounce back off it halfway
+    Solid = 1,
+
+    /// Fluid node whose populations are fixed up in `apply_bcs`
+    Boundary = 2,
+}
+
+pub struct NodeTypeArray {
+    dimensions: AABB<3>,
+    size: usize,
+    buffer: Vec<NodeType>,
+}
+
+impl NodeTypeArray {
+    pub fn new(dimensions: AABB<3>) -> Self {
+        let size = box_buffer_size(&dimensions);
+
+        NodeTypeArray {
+            dimensions,
+            size,
+            buffer: vec![NodeType::Fluid; size],
+        }
+    }
+
+    pub fn dimensions(&self) -> &AABB<3> {
+        &self.dimensions
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&self, coord: &Coord<3>) -> NodeType {
+        let index = coord_to_linear_in_box(coord, &self.dimensions);
+        self.buffer[index]
+    }
+
+    pub fn set(&mut self, coord: &Coord<3>, value: NodeType) {
+        let index = coord_to_linear_in_box(coord, &self.dimensions);
+        self.buffer[index] = value;
+    }
+
+    /// Type of the node with linear index `index`
+    pub fn get_index(&self, index: usize) -> NodeType {
+        self.buffer[index]
+    }
+
+    pub fn count(&self, node_type: NodeType) -> usize {
+        self.buffer.iter().filter(|t| **t == node_type).count()
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::{matrix, vector};
+
+    #[test]
+    fn layouts_keep_populations() {
+        let mut array = Array4D::<f32>::new(matrix![0, 2; 1, 3; 0, 1; 0, 4]);
+        for index in 0..array.size() {
+            array.buffer[index] = index as f32;
+        }
+        assert_eq!(array.get_q(&vector![1, 2, 1], 3), (9 * 5 + 3) as f32);
+
+        array.set_layout(Layout::Soa);
+        assert_eq!(array.get_q(&vector![1, 2, 1], 3), (9 * 5 + 3) as f32);
+        assert_eq!(array.get(&vector![2, 3, 1, 4]), array.buffer[array.size() - 1]);
+        // Each q is a contiguous field of the 18 nodes
+        assert_eq!(array.buffer[..4], [0.0, 5.0, 10.0, 15.0]);
+        assert_eq!(array.buffer[18], 1.0);
+
+        array.set_q(&vector![0, 1, 0], 2, -1.0);
+        array.set_layout(Layout::Aos);
+        assert_eq!(array.buffer[2], -1.0);
+    }
+}
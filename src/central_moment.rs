@@ -0,0 +1,388 @@
+use crate::*;
+
+/// D3Q27 populations or central moments on a 3 x 3 x 3 block indexed `[x][y][z]`,
+/// populations by velocity component plus one and moments by exponent
+type Block<T> = [[[T; 3]; 3]; 3];
+
+/// Apply `map` to every line of `block` along `axis`
+fn map_lines<T: NumTrait>(block: &mut Block<T>, axis: usize, map: impl Fn([T; 3]) -> [T; 3]) {
+    for i in 0..3 {
+        for j in 0..3 {
+            let index = |k: usize| match axis {
+                0 => (k, i, j),
+                1 => (i, k, j),
+                _ => (i, j, k),
+            };
+            let line = [0, 1, 2].map(|k| {
+                let (x, y, z) = index(k);
+                block[x][y][z]
+            });
+            for (k, value) in map(line).into_iter().enumerate() {
+                let (x, y, z) = index(k);
+                block[x][y][z] = value;
+            }
+        }
+    }
+}
+
+/// Populations at velocities -1, 0 and 1 to their central moments of order 0, 1 and 2 about `u`
+fn forward<T: NumTrait>(f: [T; 3], u: T) -> [T; 3] {
+    let (a, b, c) = (-T::one() - u, -u, T::one() - u);
+    [f[0] + f[1] + f[2], f[0] * a + f[1] * b + f[2] * c, f[0] * a * a + f[1] * b * b + f[2] * c * c]
+}
+
+/// Inverse of `forward`, through the raw moments
+fn backward<T: NumTrait>(k: [T; 3], u: T) -> [T; 3] {
+    let half = T::of(0.5);
+    let m1 = k[1] + u * k[0];
+    let m2 = k[2] + T::of(2.0) * u * k[1] + u * u * k[0];
+    [half * (m2 - m1), k[0] - m2, half * (m2 + m1)]
+}
+
+/// Central moments of D3Q27 populations about `u`, one axis at a time
+fn central_moments<T: NumTrait>(f: &[T], u: &Vec3<T>) -> Block<T> {
+    let mut block = [[[T::zero(); 3]; 3]; 3];
+    for (o, q) in D3Q27_OFFSETS.iter().zip(f) {
+        block[(o[0] + 1) as usize][(o[1] + 1) as usize][(o[2] + 1) as usize] = *q;
+    }
+    for axis in 0..3 {
+        map_lines(&mut block, axis, |line| forward(line, u[axis]));
+    }
+    block
+}
+
+/// D3Q27 populations from central moments about `u`
+fn populations<T: NumTrait>(mut block: Block<T>, u: &Vec3<T>, out: &mut [T]) {
+    for axis in 0..3 {
+        map_lines(&mut block, axis, |line| backward(line, u[axis]));
+    }
+    for (o, q) in D3Q27_OFFSETS.iter().zip(out) {
+        *q = block[(o[0] + 1) as usize][(o[1] + 1) as usize][(o[2] + 1) as usize];
+    }
+}
+
+fn order(a: usize, b: usize, c: usize) -> usize {
+    a + b + c
+}
+
+/// Every exponent triple of a `Block`
+fn exponents() -> impl Iterator<Item = (usize, usize, usize)> {
+    (0..27).map(|index| (index / 9, index / 3 % 3, index % 3))
+}
+
+/// Relax the second order central moments, the trace at `bulk` and the
+/// deviatoric part at `shear`. The first order moments are `-F / 2` after the
+/// half force velocity shift and are flipped to `F / 2`.
+fn relax_low_orders<T: NumTrait>(k: &mut Block<T>, rho: T, shear: T, bulk: T) {
+    k[1][0][0] = -k[1][0][0];
+    k[0][1][0] = -k[0][1][0];
+    k[0][0][1] = -k[0][0][1];
+
+    let keep = T::one() - shear;
+    k[1][1][0] *= keep;
+    k[1][0][1] *= keep;
+    k[0][1][1] *= keep;
+
+    let (xx, yy, zz) = (k[2][0][0], k[0][2][0], k[0][0][2]);
+    let trace_eq = T::of(3.0 * D3Q27::c_sqr()) * rho;
+    let trace = xx + yy + zz;
+    let trace = trace - bulk * (trace - trace_eq);
+    let d_xy = keep * (xx - yy);
+    let d_xz = keep * (xx - zz);
+    let xx = (trace + d_xy + d_xz) / T::of(3.0);
+    k[2][0][0] = xx;
+    k[0][2][0] = xx - d_xy;
+    k[0][0][2] = xx - d_xz;
+}
+
+/// Cascaded central moment collision for D3Q27.
+///
+/// Central moments about the local velocity relax towards the factorized
+/// equilibrium `rho c_s^(2 n)`, `n` being the number of second order exponents,
+/// and to zero where any exponent is one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CentralMoment<T = f32> {
+    /// Sets the viscosity like the BGK `omega`
+    pub shear: T,
+
+    pub bulk: T,
+
+    /// Rate of all third and higher order moments
+    pub higher: T,
+}
+
+impl<T: NumTrait> CentralMoment<T> {
+    /// Higher order moments are set to equilibrium, the bulk viscosity is that of BGK
+    pub fn new(omega: T) -> Self {
+        CentralMoment {
+            shear: omega,
+            bulk: omega,
+            higher: T::one(),
+        }
+    }
+}
+
+impl<T: NumTrait> CollisionOperator<D3Q27, T> for CentralMoment<T> {
+    fn omega(&self) -> T {
+        self.shear
+    }
+
+    fn collide(&self, node: &CollisionNode<T>, out: &mut [T]) {
+        let mut k = central_moments(node.f, &node.u);
+        let c_sqr = T::of(D3Q27::c_sqr());
+        for (a, b, c) in exponents().filter(|(a, b, c)| order(*a, *b, *c) >= 3) {
+            let k_eq = if a == 1 || b == 1 || c == 1 {
+                T::zero()
+            } else {
+                node.rho * c_sqr.powi(order(a, b, c) as i32 / 2)
+            };
+            k[a][b][c] -= self.higher * (k[a][b][c] - k_eq);
+        }
+        relax_low_orders(&mut k, node.rho, node.omega.unwrap_or(self.shear), self.bulk);
+        populations(k, &node.u, out);
+    }
+}
+
+/// Parts of the fourth order central moments that are products of second order
+/// ones, the cumulants are what remains. First order moments count as zero.
+fn fourth_order_products<T: NumTrait>(k: &Block<T>, rho: T) -> [([usize; 3], T); 6] {
+    let m = |a: usize, b: usize, c: usize| k[a][b][c];
+    let two = T::of(2.0);
+    [
+        ([2, 1, 1], (m(2, 0, 0) * m(0, 1, 1) + two * m(1, 1, 0) * m(1, 0, 1)) / rho),
+        ([1, 2, 1], (m(0, 2, 0) * m(1, 0, 1) + two * m(1, 1, 0) * m(0, 1, 1)) / rho),
+        ([1, 1, 2], (m(0, 0, 2) * m(1, 1, 0) + two * m(1, 0, 1) * m(0, 1, 1)) / rho),
+        ([2, 2, 0], (m(2, 0, 0) * m(0, 2, 0) + two * m(1, 1, 0) * m(1, 1, 0)) / rho),
+        ([2, 0, 2], (m(2, 0, 0) * m(0, 0, 2) + two * m(1, 0, 1) * m(1, 0, 1)) / rho),
+        ([0, 2, 2], (m(0, 2, 0) * m(0, 0, 2) + two * m(0, 1, 1) * m(0, 1, 1)) / rho),
+    ]
+}
+
+/// Like `fourth_order_products`, from the second and third order moments
+fn fifth_order_products<T: NumTrait>(k: &Block<T>, rho: T) -> [([usize; 3], T); 3] {
+    let m = |a: usize, b: usize, c: usize| k[a][b][c];
+    let (two, four) = (T::of(2.0), T::of(4.0));
+    [
+        (
+            [1, 2, 2],
+            (m(0, 0, 2) * m(1, 2, 0)
+                + m(0, 2, 0) * m(1, 0, 2)
+                + four * m(0, 1, 1) * m(1, 1, 1)
+                + two * (m(1, 0, 1) * m(0, 2, 1) + m(1, 1, 0) * m(0, 1, 2)))
+                / rho,
+        ),
+        (
+            [2, 1, 2],
+            (m(0, 0, 2) * m(2, 1, 0)
+                + m(2, 0, 0) * m(0, 1, 2)
+                + four * m(1, 0, 1) * m(1, 1, 1)
+                + two * (m(1, 1, 0) * m(1, 0, 2) + m(0, 1, 1) * m(2, 0, 1)))
+                / rho,
+        ),
+        (
+            [2, 2, 1],
+            (m(2, 0, 0) * m(0, 2, 1)
+                + m(0, 2, 0) * m(2, 0, 1)
+                + four * m(1, 1, 0) * m(1, 1, 1)
+                + two * (m(1, 0, 1) * m(1, 2, 0) + m(0, 1, 1) * m(2, 1, 0)))
+                / rho,
+        ),
+    ]
+}
+
+/// Like `fourth_order_products` for the single sixth order moment, from the
+/// second, third and fourth order moments
+fn sixth_order_products<T: NumTrait>(k: &Block<T>, rho: T) -> T {
+    let m = |a: usize, b: usize, c: usize| k[a][b][c];
+    let (two, four, eight) = (T::of(2.0), T::of(4.0), T::of(8.0));
+    let pairs = m(2, 0, 0) * m(0, 2, 2)
+        + m(0, 2, 0) * m(2, 0, 2)
+        + m(0, 0, 2) * m(2, 2, 0)
+        + four * (m(1, 1, 0) * m(1, 1, 2) + m(1, 0, 1) * m(1, 2, 1) + m(0, 1, 1) * m(2, 1, 1))
+        + two * (m(2, 1, 0) * m(0, 1, 2) + m(2, 0, 1) * m(0, 2, 1) + m(1, 2, 0) * m(1, 0, 2))
+        + four * m(1, 1, 1) * m(1, 1, 1);
+    let triples = m(2, 0, 0) * m(0, 2, 0) * m(0, 0, 2)
+        + two * (m(2, 0, 0) * m(0, 1, 1) * m(0, 1, 1) + m(0, 2, 0) * m(1, 0, 1) * m(1, 0, 1) + m(0, 0, 2) * m(1, 1, 0) * m(1, 1, 0))
+        + eight * m(1, 1, 0) * m(1, 0, 1) * m(0, 1, 1);
+    pairs / rho - two * triples / (rho * rho)
+}
+
+/// Cumulant collision for D3Q27.
+///
+/// Second order cumulants are the second order central moments and relax like
+/// `CentralMoment`. Third and higher order cumulants, which vanish at equilibrium,
+/// relax towards zero and the central moments are rebuilt from the relaxed cumulants.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cumulant<T = f32> {
+    /// Sets the viscosity like the BGK `omega`
+    pub shear: T,
+
+    pub bulk: T,
+
+    /// Rate of all third and higher order cumulants
+    pub higher: T,
+}
+
+impl<T: NumTrait> Cumulant<T> {
+    /// Higher order cumulants are set to equilibrium, the bulk viscosity is that of BGK
+    pub fn new(omega: T) -> Self {
+        Cumulant {
+            shear: omega,
+            bulk: omega,
+            higher: T::one(),
+        }
+    }
+}
+
+impl<T: NumTrait> CollisionOperator<D3Q27, T> for Cumulant<T> {
+    fn omega(&self) -> T {
+        self.shear
+    }
+
+    fn collide(&self, node: &CollisionNode<T>, out: &mut [T]) {
+        let rho = node.rho;
+        let keep = T::one() - self.higher;
+        let mut k = central_moments(node.f, &node.u);
+
+        let fourth = fourth_order_products(&k, rho).map(|([a, b, c], p)| ([a, b, c], keep * (k[a][b][c] - p)));
+        let fifth = fifth_order_products(&k, rho).map(|([a, b, c], p)| ([a, b, c], keep * (k[a][b][c] - p)));
+        let sixth = keep * (k[2][2][2] - sixth_order_products(&k, rho));
+
+        relax_low_orders(&mut k, rho, node.omega.unwrap_or(self.shear), self.bulk);
+        for (a, b, c) in exponents().filter(|(a, b, c)| order(*a, *b, *c) == 3) {
+            k[a][b][c] *= keep;
+        }
+
+        for ([a, b, c], cumulant) in fourth {
+            k[a][b][c] = cumulant;
+        }
+        for ([a, b, c], p) in fourth_order_products(&k, rho) {
+            k[a][b][c] += p;
+        }
+        for ([a, b, c], cumulant) in fifth {
+            k[a][b][c] = cumulant;
+        }
+        for ([a, b, c], p) in fifth_order_products(&k, rho) {
+            k[a][b][c] += p;
+        }
+        k[2][2][2] = sixth + sixth_order_products(&k, rho);
+
+        populations(k, &node.u, out);
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::vector;
+
+    fn raw_moments(f: &[f32]) -> (f32, Vec3, [[f32; 3]; 3]) {
+        let mut rho = 0.0;
+        let mut momentum = Vec3::zero();
+        let mut second = [[0.0; 3]; 3];
+        for (c, q) in gen_d3q27_directions::<f32>().iter().zip(f) {
+            rho += q;
+            momentum += c * *q;
+            for a in 0..3 {
+                for b in 0..3 {
+                    second[a][b] += c[a] * c[b] * q;
+                }
+            }
+        }
+        (rho, momentum, second)
+    }
+
+    fn check_against_bgk<C: CollisionOperator<D3Q27>>(collision: C) {
+        let omega = 1.6;
+        let rho = 1.03;
+        let u = vector![0.05, -0.03, 0.02];
+        let f_eq: Vec<f32> = gen_d3q27_directions::<f32>()
+            .iter()
+            .zip(D3Q27_W.iter())
+            .map(|(c, w)| equilibrium(*w as f32, rho, c.dot(&u), u.dot(&u), D3Q27::c_sqr() as f32))
+            .collect();
+        let f: Vec<f32> = f_eq
+            .iter()
+            .enumerate()
+            .map(|(q_i, q)| q * (1.0 + 0.02 * ((q_i * 7) % 5) as f32 - 0.04))
+            .collect();
+        let (rho, momentum, _) = raw_moments(&f);
+        let u = momentum / rho;
+        let f_eq: Vec<f32> = gen_d3q27_directions::<f32>()
+            .iter()
+            .zip(D3Q27_W.iter())
+            .map(|(c, w)| equilibrium(*w as f32, rho, c.dot(&u), u.dot(&u), D3Q27::c_sqr() as f32))
+            .collect();
+        let source = vec![0.0; 27];
+        let node = CollisionNode {
+            rho,
+            u,
+            force: Vec3::zero(),
+            f: &f,
+            f_eq: &f_eq,
+            source: &source,
+            omega: None,
+        };
+
+        let mut expected = vec![0.0; 27];
+        let mut relaxed = vec![0.0; 27];
+        CollisionOperator::<D3Q27>::collide(&Bgk { omega }, &node, &mut expected);
+        collision.collide(&node, &mut relaxed);
+
+        // Conserved moments and the viscous stress match BGK, higher orders differ
+        let (rho_a, m_a, s_a) = raw_moments(&expected);
+        let (rho_b, m_b, s_b) = raw_moments(&relaxed);
+        assert!((rho_a - rho_b).abs() < 1e-6);
+        assert!((m_a - m_b).norm() < 1e-6);
+        for a in 0..3 {
+            for b in 0..3 {
+                assert!((s_a[a][b] - s_b[a][b]).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn transform_round_trip() {
+        let f: Vec<f32> = (0..27).map(|q_i| 0.01 + 0.001 * q_i as f32).collect();
+        let u = vector![0.1, -0.05, 0.02];
+        let mut out = vec![0.0; 27];
+        populations(central_moments(&f, &u), &u, &mut out);
+        for (a, b) in f.iter().zip(&out) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn second_order_matches_bgk() {
+        check_against_bgk(CentralMoment::new(1.6));
+        check_against_bgk(CentralMoment {
+            shear: 1.6,
+            bulk: 1.6,
+            higher: 1.3,
+        });
+        check_against_bgk(Cumulant::new(1.6));
+        check_against_bgk(Cumulant {
+            shear: 1.6,
+            bulk: 1.6,
+            higher: 1.3,
+        });
+    }
+
+    #[test]
+    fn factorized_equilibrium_has_no_higher_cumulants() {
+        let rho = 1.1;
+        let c_sqr = D3Q27::c_sqr();
+        let mut k: Block<f64> = [[[0.0; 3]; 3]; 3];
+        for a in [0, 2] {
+            for b in [0, 2] {
+                for c in [0, 2] {
+                    k[a][b][c] = rho * c_sqr.powi(order(a, b, c) as i32 / 2);
+                }
+            }
+        }
+        for (index, p) in fourth_order_products(&k, rho) {
+            assert!((k[index[0]][index[1]][index[2]] - p).abs() < 1e-7);
+        }
+        assert!((k[2][2][2] - sixth_order_products(&k, rho)).abs() < 1e-7);
+    }
+}
@@ -0,0 +1,410 @@
+use crate::*;
+use nalgebra::{DMatrix, DVector};
+use std::marker::PhantomData;
+
+/// Pre-collision state of one node handed to a `CollisionOperator`
+pub struct CollisionNode<'a, T = f32> {
+    pub rho: T,
+
+    /// Half force shifted velocity from `Solver::moments`
+    pub u: Vec3<T>,
+
+    pub force: Vec3<T>,
+
+    pub f: &'a [T],
+
+    /// Second order equilibrium of `rho` and `u`
+    pub f_eq: &'a [T],
+
+    /// Guo forcing source from `guo_source`, zeros without a body force
+    pub source: &'a [T],
+
+    /// Local replacement for `CollisionOperator::omega`, e.g. from a turbulence model
+    pub omega: Option<T>,
+}
+
+/// Relaxes the populations of one node, see `Solver::collision`.
+/// Operators that only exist for some velocity sets implement it for those alone.
+pub trait CollisionOperator<V: VelocitySet, T: NumTrait = f32>: Send + Sync {
+    /// The rate that sets the shear viscosity `c_s^2 (1 / omega - 1 / 2)`
+    fn omega(&self) -> T;
+
+    fn collide(&self, node: &CollisionNode<T>, out: &mut [T]);
+
+    /// `Some(omega)` for plain BGK relaxation, which the SIMD kernels can take over
+    fn bgk_omega(&self) -> Option<T> {
+        None
+    }
+
+    /// Whether shifting `f` and `f_eq` alike shifts the result by the same amount,
+    /// true for operators affine in them. Those relax the shifted populations of
+    /// `Solver::set_shifted` as they are stored.
+    fn shift_invariant(&self) -> bool {
+        false
+    }
+}
+
+/// Single relaxation time, every population relaxes at `omega`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bgk<T = f32> {
+    pub omega: T,
+}
+
+impl<V: VelocitySet, T: NumTrait> CollisionOperator<V, T> for Bgk<T> {
+    fn omega(&self) -> T {
+        self.omega
+    }
+
+    fn collide(&self, node: &CollisionNode<T>, out: &mut [T]) {
+        let omega = node.omega.unwrap_or(self.omega);
+        let forcing = T::one() - T::of(0.5) * omega;
+        for (q_i, o) in out.iter_mut().enumerate() {
+            *o = node.f[q_i] + omega * (node.f_eq[q_i] - node.f[q_i]) + forcing * node.source[q_i];
+        }
+    }
+
+    fn bgk_omega(&self) -> Option<T> {
+        Some(self.omega)
+    }
+
+    fn shift_invariant(&self) -> bool {
+        true
+    }
+}
+
+/// Two relaxation times, the symmetric part `(f_i + f_opp) / 2` of the populations
+/// relaxes at `omega_plus`, which sets the viscosity, and the antisymmetric part at
+/// `omega_minus`.
+///
+/// The magic parameter `(1 / omega_plus - 1 / 2) (1 / omega_minus - 1 / 2)` fixes
+/// where halfway bounce-back puts the wall, 3 / 16 puts it exactly halfway for
+/// Poiseuille flow whatever the viscosity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trt<T = f32> {
+    pub omega_plus: T,
+    pub omega_minus: T,
+}
+
+impl<T: NumTrait> Trt<T> {
+    /// `omega_minus` chosen so that the magic parameter is `lambda`
+    pub fn with_magic(omega: T, lambda: T) -> Self {
+        let half = T::of(0.5);
+        Trt {
+            omega_plus: omega,
+            omega_minus: (lambda / (omega.recip() - half) + half).recip(),
+        }
+    }
+
+    pub fn magic(&self) -> T {
+        let half = T::of(0.5);
+        (self.omega_plus.recip() - half) * (self.omega_minus.recip() - half)
+    }
+}
+
+impl<V: VelocitySet, T: NumTrait> CollisionOperator<V, T> for Trt<T> {
+    fn omega(&self) -> T {
+        self.omega_plus
+    }
+
+    /// A local `omega` replaces `omega_plus` and `omega_minus` follows it so the
+    /// magic parameter is kept
+    fn collide(&self, node: &CollisionNode<T>, out: &mut [T]) {
+        let (plus, minus) = match node.omega {
+            Some(omega) => {
+                let local = Trt::with_magic(omega, self.magic());
+                (local.omega_plus, local.omega_minus)
+            }
+            None => (self.omega_plus, self.omega_minus),
+        };
+        let half = T::of(0.5);
+        for (q_i, o) in out.iter_mut().enumerate() {
+            let opp = V::opposites()[q_i];
+            let f_plus = half * (node.f[q_i] + node.f[opp]);
+            let f_minus = half * (node.f[q_i] - node.f[opp]);
+            let eq_plus = half * (node.f_eq[q_i] + node.f_eq[opp]);
+            let eq_minus = half * (node.f_eq[q_i] - node.f_eq[opp]);
+            let source_plus = half * (node.source[q_i] + node.source[opp]);
+            let source_minus = half * (node.source[q_i] - node.source[opp]);
+            *o = node.f[q_i] - plus * (f_plus - eq_plus) - minus * (f_minus - eq_minus)
+                + (T::one() - half * plus) * source_plus
+                + (T::one() - half * minus) * source_minus;
+        }
+    }
+
+    fn shift_invariant(&self) -> bool {
+        true
+    }
+}
+
+/// Role of a moment in the MRT basis, used to pick its relaxation rate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MomentKind {
+    /// Density and momentum
+    Conserved,
+
+    /// Trace of the second order moments, sets the bulk viscosity
+    Bulk,
+
+    /// Traceless second order moments, set the shear viscosity
+    Shear,
+
+    /// Third and higher order moments
+    Higher,
+}
+
+/// A polynomial `sum coefficient * c_x^a c_y^b c_z^c` in the lattice velocity components
+#[derive(Clone, Debug, PartialEq)]
+pub struct Moment {
+    pub terms: Vec<(f64, [u32; 3])>,
+    pub kind: MomentKind,
+}
+
+impl Moment {
+    fn monomial(exponents: [u32; 3], kind: MomentKind) -> Self {
+        Moment {
+            terms: vec![(1.0, exponents)],
+            kind,
+        }
+    }
+
+    pub fn order(&self) -> u32 {
+        self.terms[0].1.iter().sum()
+    }
+
+    pub fn eval(&self, c: &[i32; 3]) -> f64 {
+        self.terms
+            .iter()
+            .map(|(coefficient, e)| {
+                coefficient * (c[0] as f64).powi(e[0] as i32) * (c[1] as f64).powi(e[1] as i32) * (c[2] as f64).powi(e[2] as i32)
+            })
+            .sum()
+    }
+}
+
+/// Every moment a velocity set might need, lowest order first. The second
+/// order moments are split into the trace and traceless parts.
+fn candidate_moments() -> Vec<Moment> {
+    let mut result = vec![Moment::monomial([0, 0, 0], MomentKind::Conserved)];
+    for e in [[1, 0, 0], [0, 1, 0], [0, 0, 1]] {
+        result.push(Moment::monomial(e, MomentKind::Conserved));
+    }
+
+    result.push(Moment {
+        terms: vec![(1.0, [2, 0, 0]), (1.0, [0, 2, 0]), (1.0, [0, 0, 2])],
+        kind: MomentKind::Bulk,
+    });
+    result.push(Moment {
+        terms: vec![(1.0, [2, 0, 0]), (-1.0, [0, 2, 0])],
+        kind: MomentKind::Shear,
+    });
+    result.push(Moment {
+        terms: vec![(1.0, [0, 2, 0]), (-1.0, [0, 0, 2])],
+        kind: MomentKind::Shear,
+    });
+    for e in [[1, 1, 0], [1, 0, 1], [0, 1, 1]] {
+        result.push(Moment::monomial(e, MomentKind::Shear));
+    }
+
+    for order in 3..=6 {
+        for a in 0..=2 {
+            for b in 0..=2 {
+                for c in 0..=2 {
+                    if a + b + c == order {
+                        result.push(Moment::monomial([a, b, c], MomentKind::Higher));
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Raw moment basis of `V`, the first `V::Q` candidates that are linearly
+/// independent on the lattice
+pub fn moment_basis<V: VelocitySet>() -> Vec<Moment> {
+    let mut basis = Vec::with_capacity(V::Q);
+    let mut orthonormal: Vec<DVector<f64>> = Vec::with_capacity(V::Q);
+    for moment in candidate_moments() {
+        let mut v = DVector::from_iterator(V::Q, V::offsets().iter().map(|c| moment.eval(c)));
+        for o in &orthonormal {
+            v -= o * o.dot(&v);
+        }
+        let norm = v.norm();
+        if norm > 1e-9 {
+            orthonormal.push(v / norm);
+            basis.push(moment);
+        }
+    }
+    assert_eq!(basis.len(), V::Q, "moment basis is incomplete");
+    basis
+}
+
+/// Multiple relaxation time collision in a raw moment basis, see `moment_basis`.
+///
+/// Populations relax as `f - M^-1 S M (f - f_eq) + M^-1 (I - S / 2) M F` with one
+/// rate per moment on the diagonal of `S`, BGK is the special case `S = omega I`.
+pub struct Mrt<V: VelocitySet, T: NumTrait = f32> {
+    relaxation: Vec<T>,
+    collide: DMatrix<T>,
+    forcing: DMatrix<T>,
+
+    /// `M^-1 P M` with `P` selecting the shear moments, to shift their rate per node
+    shear: DMatrix<T>,
+
+    /// Rate of the first shear moment
+    omega: T,
+
+    velocity_set: PhantomData<V>,
+}
+
+impl<V: VelocitySet, T: NumTrait> Mrt<V, T> {
+    /// One relaxation rate per moment of `moment_basis::<V>()`
+    pub fn new(relaxation: Vec<T>) -> Self {
+        assert_eq!(relaxation.len(), V::Q, "expected one relaxation rate per moment");
+        let basis = moment_basis::<V>();
+        let m = DMatrix::from_fn(V::Q, V::Q, |row, q_i| basis[row].eval(&V::offsets()[q_i]));
+        let m_inv = m.clone().try_inverse().expect("moment basis is invertible");
+        let s = DMatrix::from_diagonal(&DVector::from_iterator(V::Q, relaxation.iter().map(|r| r.to_f64())));
+        let identity = DMatrix::<f64>::identity(V::Q, V::Q);
+        let p = DMatrix::from_diagonal(&DVector::from_iterator(
+            V::Q,
+            basis.iter().map(|moment| if moment.kind == MomentKind::Shear { 1.0 } else { 0.0 }),
+        ));
+
+        let shear = basis.iter().position(|moment| moment.kind == MomentKind::Shear).unwrap();
+
+        Mrt {
+            omega: relaxation[shear],
+            collide: (&m_inv * &s * &m).cast::<T>(),
+            forcing: (&m_inv * (identity - s * 0.5) * &m).cast::<T>(),
+            shear: (&m_inv * p * &m).cast::<T>(),
+            relaxation,
+            velocity_set: PhantomData,
+        }
+    }
+
+    /// `shear` sets the viscosity like the BGK `omega`, `bulk` the bulk viscosity and
+    /// `higher` the rate of all third and higher order moments
+    pub fn with_rates(shear: T, bulk: T, higher: T) -> Self {
+        let relaxation = moment_basis::<V>()
+            .iter()
+            .map(|moment| match moment.kind {
+                MomentKind::Conserved => T::zero(),
+                MomentKind::Bulk => bulk,
+                MomentKind::Shear => shear,
+                MomentKind::Higher => higher,
+            })
+            .collect();
+        Self::new(relaxation)
+    }
+
+    pub fn relaxation(&self) -> &[T] {
+        &self.relaxation
+    }
+}
+
+impl<V: VelocitySet, T: NumTrait> CollisionOperator<V, T> for Mrt<V, T> {
+    /// Rate of the first shear moment
+    fn omega(&self) -> T {
+        self.omega
+    }
+
+    /// A local `omega` shifts the rates of all shear moments by its difference to `omega`
+    fn collide(&self, node: &CollisionNode<T>, out: &mut [T]) {
+        let shift = node.omega.map(|omega| omega - self.omega);
+        let half = T::of(0.5);
+        for (i, o) in out.iter_mut().enumerate() {
+            let mut value = node.f[i];
+            for j in 0..V::Q {
+                let neq = node.f[j] - node.f_eq[j];
+                value += self.forcing[(i, j)] * node.source[j] - self.collide[(i, j)] * neq;
+                if let Some(shift) = shift {
+                    value -= shift * self.shear[(i, j)] * (neq + half * node.source[j]);
+                }
+            }
+            *o = value;
+        }
+    }
+
+    fn shift_invariant(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::vector;
+
+    fn check_mrt_matches_bgk<V: VelocitySet>() {
+        let omega = 1.3;
+        let u = vector![0.04, -0.02, 0.01];
+        let force = vector![1e-3, 0.0, -2e-3];
+        let directions = V::gen_directions::<f32>();
+        let c_sqr = V::c_sqr() as f32;
+        let f_eq: Vec<f32> = directions
+            .iter()
+            .zip(V::weights())
+            .map(|(c, w)| equilibrium(*w as f32, 1.0, c.dot(&u), u.dot(&u), c_sqr))
+            .collect();
+        let f: Vec<f32> = f_eq.iter().enumerate().map(|(q_i, q)| q * (1.0 + 0.01 * q_i as f32)).collect();
+        let source: Vec<f32> = directions
+            .iter()
+            .zip(V::weights())
+            .map(|(c, w)| guo_source(*w as f32, c, &u, &force, c_sqr))
+            .collect();
+
+        let node = CollisionNode {
+            rho: 1.0,
+            u,
+            force,
+            f: &f,
+            f_eq: &f_eq,
+            source: &source,
+            omega: None,
+        };
+        let mut bgk = vec![0.0; V::Q];
+        let mut mrt = vec![0.0; V::Q];
+        let mut trt = vec![0.0; V::Q];
+        CollisionOperator::<V>::collide(&Bgk { omega }, &node, &mut bgk);
+        Mrt::<V>::new(vec![omega; V::Q]).collide(&node, &mut mrt);
+        CollisionOperator::<V>::collide(&Trt::with_magic(omega, (1.0 / omega - 0.5).powi(2)), &node, &mut trt);
+        for ((a, b), c) in bgk.iter().zip(&mrt).zip(&trt) {
+            assert!((a - b).abs() < 1e-6);
+            assert!((a - c).abs() < 1e-6);
+        }
+
+        // A local omega only moves the shear rates
+        let local = CollisionNode {
+            omega: Some(0.9),
+            ..node
+        };
+        Mrt::<V>::with_rates(omega, 1.4, 1.2).collide(&local, &mut mrt);
+        Mrt::<V>::with_rates(0.9, 1.4, 1.2).collide(&node, &mut bgk);
+        for (a, b) in bgk.iter().zip(&mrt) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn equal_rates_are_bgk() {
+        check_mrt_matches_bgk::<D2Q9>();
+        check_mrt_matches_bgk::<D3Q15>();
+        check_mrt_matches_bgk::<D3Q19>();
+        check_mrt_matches_bgk::<D3Q27>();
+    }
+
+    #[test]
+    fn moment_kinds() {
+        let count = |basis: &[Moment], kind| basis.iter().filter(|m| m.kind == kind).count();
+        let d2q9 = moment_basis::<D2Q9>();
+        assert_eq!(count(&d2q9, MomentKind::Conserved), 3);
+        assert_eq!(count(&d2q9, MomentKind::Shear), 2);
+        assert_eq!(count(&d2q9, MomentKind::Bulk), 1);
+
+        let d3q27 = moment_basis::<D3Q27>();
+        assert_eq!(count(&d3q27, MomentKind::Conserved), 4);
+        assert_eq!(count(&d3q27, MomentKind::Shear), 5);
+        assert_eq!(count(&d3q27, MomentKind::Higher), 17);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::*;
+
+/// Additional fluid component of a multi-component solver, see `Solver::add_component`.
+///
+/// It streams and bounces back like the solver's own populations and relaxes
+/// with BGK at its own `omega` towards the equilibrium at the mixture velocity.
+pub struct Component<T = f32> {
+    pub distributions: Array4D<T>,
+    pub(crate) distributions_buffer: Array4D<T>,
+    pub(crate) density: Array3D<T>,
+    pub(crate) force: VelArray<T>,
+    omega: T,
+}
+
+impl<T: NumTrait> Component<T> {
+    /// `dimensions` of the populations, `x, y, z, q`
+    pub fn new(dimensions: AABB<4>, omega: T) -> Self {
+        let grid_dimensions = dimensions.fixed_rows::<3>(0).into_owned();
+        Component {
+            distributions: Array4D::new(dimensions),
+            distributions_buffer: Array4D::new(dimensions),
+            density: Array3D::new(grid_dimensions),
+            force: VelArray::new(grid_dimensions),
+            omega,
+        }
+    }
+
+    pub fn omega(&self) -> T {
+        self.omega
+    }
+
+    /// Density of the last `Solver::moments`
+    pub fn density(&self) -> &Array3D<T> {
+        &self.density
+    }
+
+    /// Coupling force of the last `Solver::moments`
+    pub fn force(&self) -> &VelArray<T> {
+        &self.force
+    }
+}
+
+/// Shan-Chen coupling between components with `psi = rho`, see
+/// `Solver::set_component_coupling`. Component 0 is the solver's own populations.
+///
+/// Component `a` feels `-rho_a(x) sum_b G_ab sum_i w_i rho_b(x + c_i) c_i` from the
+/// fluid neighbors and `-G_wall_a rho_a(x) sum_i w_i s(x + c_i) c_i` from the solid ones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ComponentCoupling<T = f32> {
+    /// Symmetric, positive entries make two components repel each other
+    pub g: Vec<Vec<T>>,
+
+    /// Adsorption strength of walls per component, positive for walls repelling it
+    pub g_wall: Vec<T>,
+}
+
+impl<T: NumTrait> ComponentCoupling<T> {
+    /// Two immiscible components repelling each other with strength `g`, and
+    /// neutral walls
+    pub fn immiscible(g: T) -> Self {
+        ComponentCoupling {
+            g: vec![vec![T::zero(), g], vec![g, T::zero()]],
+            g_wall: vec![T::zero(); 2],
+        }
+    }
+
+    pub fn component_count(&self) -> usize {
+        self.g.len()
+    }
+
+    /// Adds the coupling force on each component to `forces`, `densities` and
+    /// `forces` are indexed like `g`
+    pub fn add_forces<V: VelocitySet>(
+        &self,
+        densities: &[&Array3D<T>],
+        node_types: &NodeTypeArray,
+        grid_dimensions: &AABB<3>,
+        periodic: &[bool; 3],
+        forces: &mut [&mut VelArray<T>],
+    ) {
+        let offsets = V::gen_offsets();
+        let directions = V::gen_directions::<T>();
+        let n = self.component_count();
+        for (a, force) in forces.iter_mut().enumerate() {
+            let init = || vec![Vec3::zero(); n];
+            for_each_node(grid_dimensions, &mut force.buffer, 1, init, |fluid, coord, out| {
+                if node_types.get(&coord) == NodeType::Solid {
+                    return;
+                }
+                fluid.fill(Vec3::zero());
+                let mut wall = Vec3::zero();
+                for ((offset, c), w_i) in offsets.iter().zip(&directions).zip(V::weights()) {
+                    let w_i = T::of(*w_i);
+                    let neighbor = periodic_wrap(grid_dimensions, &(coord + offset), periodic);
+                    if box_contains_coord(grid_dimensions, &neighbor) && node_types.get(&neighbor) != NodeType::Solid {
+                        for (sum, density) in fluid.iter_mut().zip(densities) {
+                            *sum += c * (w_i * density.get(&neighbor));
+                        }
+                    } else {
+                        wall += c * w_i;
+                    }
+                }
+                let mut interaction = wall * self.g_wall[a];
+                for (b, sum) in fluid.iter().enumerate() {
+                    interaction += sum * self.g[a][b];
+                }
+                out[0] -= interaction * densities[a].get(&coord);
+            });
+        }
+    }
+}
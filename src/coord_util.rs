@@ -1,8 +1,45 @@
 pub use num_traits::{Num, One, Zero};
+use std::simd::prelude::*;
+use crate::{SimdLanes, LANES};
 
-pub trait NumTrait = Num + Copy + Send + Sync;
+/// Storage and compute type of the solver, `f32` or `f64`. Solvers of both
+/// precisions can run side by side, e.g. a double precision validation run next
+/// to the single precision production one.
+pub trait NumTrait: nalgebra::RealField + Copy + Default + std::iter::Sum + for<'a> std::iter::Sum<&'a Self> {
+    /// One value per node of a SIMD group, see `simd`
+    type Lanes: SimdLanes<Self>;
 
-pub type Vec3 = nalgebra::SVector<f32, 3>;
+    /// `value` rounded to this precision, for constants
+    fn of(value: f64) -> Self;
+
+    fn to_f64(self) -> f64;
+}
+
+impl NumTrait for f32 {
+    type Lanes = Simd<f32, LANES>;
+
+    fn of(value: f64) -> Self {
+        value as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl NumTrait for f64 {
+    type Lanes = Simd<f64, LANES>;
+
+    fn of(value: f64) -> Self {
+        value
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+pub type Vec3<T = f32> = nalgebra::SVector<T, 3>;
 
 pub type Coord<const GRID_DIMENSION: usize> = nalgebra::SVector<i32, { GRID_DIMENSION }>;
 pub type AABB<const GRID_DIMENSION: usize> = nalgebra::SMatrix<i32, { GRID_DIMENSION }, 2>;
@@ -75,3 +112,21 @@ pub fn box_contains_coord<const GRID_DIMENSION: usize>(aabb: &AABB<GRID_DIMENSIO
     }
     true
 }
+
+/// Wrap `coord` back into `aabb` along every periodic dimension.
+/// Dimensions that are not periodic are left untouched.
+pub fn periodic_wrap<const GRID_DIMENSION: usize>(
+    aabb: &AABB<GRID_DIMENSION>,
+    coord: &Coord<GRID_DIMENSION>,
+    periodic: &[bool; GRID_DIMENSION],
+) -> Coord<GRID_DIMENSION> {
+    let mut result = *coord;
+    for d in 0..GRID_DIMENSION {
+        if periodic[d] {
+            let min = aabb[(d, 0)];
+            let extent = aabb[(d, 1)] - min + 1;
+            result[d] = (coord[d] - min).rem_euclid(extent) + min;
+        }
+    }
+    result
+}
@@ -0,0 +1,168 @@
+use crate::*;
+
+/// Discrete H-function `sum_i f_i ln(f_i / w_i)`
+pub fn h_function<V: VelocitySet>(f: &[f64]) -> f64 {
+    f.iter().zip(V::weights()).map(|(q, w)| q * (q / w).ln()).sum()
+}
+
+/// Nontrivial root `alpha` of `H(f + alpha (f_eq - f)) = H(f)`, the mirror state
+/// of `f` with the same entropy. It is 2 close to equilibrium, where BGK and the
+/// entropic operator agree, and is kept small enough that no population turns negative.
+pub fn entropic_stabilizer<V: VelocitySet, T: NumTrait>(f: &[T], f_eq: &[T]) -> T {
+    const MAX_ITERATIONS: usize = 20;
+
+    let f: Vec<f64> = f.iter().map(|q| q.to_f64()).collect();
+    let delta: Vec<f64> = f_eq.iter().zip(&f).map(|(q_eq, q)| q_eq.to_f64() - q).collect();
+
+    // Relative deviation too small for the entropy difference to resolve
+    let deviation = f.iter().zip(&delta).map(|(q, d)| (d / q).abs()).fold(0.0, f64::max);
+    if deviation.is_nan() || deviation < 1e-4 || f.iter().any(|q| *q <= 0.0) {
+        return T::of(2.0);
+    }
+
+    let alpha_max = f
+        .iter()
+        .zip(&delta)
+        .filter(|(_, d)| **d < 0.0)
+        .map(|(q, d)| -q / d)
+        .fold(f64::MAX, f64::min);
+    let alpha_upper = 0.99 * alpha_max;
+    // Some f_eq is negative, as at high velocities, so no step up to equilibrium
+    // keeps the populations positive
+    if alpha_upper <= 1.0 {
+        return T::of(alpha_upper);
+    }
+
+    let h = h_function::<V>(&f);
+    let mut mirror = vec![0.0; V::Q];
+    let mut alpha = 2.0_f64.min(alpha_upper);
+    for _ in 0..MAX_ITERATIONS {
+        for ((m, q), d) in mirror.iter_mut().zip(&f).zip(&delta) {
+            *m = q + alpha * d;
+        }
+        let g = h_function::<V>(&mirror) - h;
+        let slope: f64 = mirror
+            .iter()
+            .zip(&delta)
+            .zip(V::weights())
+            .map(|((m, d), w)| d * ((m / w).ln() + 1.0))
+            .sum();
+        if slope <= 0.0 {
+            break;
+        }
+        let step = g / slope;
+        alpha = (alpha - step).clamp(1.0, alpha_upper);
+        if step.abs() < 1e-10 {
+            break;
+        }
+    }
+    T::of(alpha)
+}
+
+/// Entropic collision (ELBM), populations relax as `f + alpha beta (f_eq - f)` with
+/// `beta = omega / 2` and the stabilizer `alpha` from `entropic_stabilizer` per node.
+///
+/// Where the populations are far from equilibrium `alpha` moves away from 2, which
+/// adjusts the local dissipation so that the H-function does not grow.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Entropic<T = f32> {
+    pub omega: T,
+}
+
+impl<V: VelocitySet, T: NumTrait> CollisionOperator<V, T> for Entropic<T> {
+    fn omega(&self) -> T {
+        self.omega
+    }
+
+    fn collide(&self, node: &CollisionNode<T>, out: &mut [T]) {
+        let half = T::of(0.5);
+        let beta = half * node.omega.unwrap_or(self.omega);
+        // The mirror state is taken against the equilibrium of the moments of `f`, in
+        // double precision. The half force shift of `f_eq` and its rounding would
+        // otherwise dominate H near equilibrium.
+        let directions = V::gen_directions::<f64>();
+        let f: Vec<f64> = node.f.iter().map(|q| q.to_f64()).collect();
+        let rho: f64 = f.iter().sum();
+        let u = f.iter().zip(&directions).map(|(q, c)| c * *q).sum::<Vec3<f64>>() / rho;
+        let f_eq: Vec<f64> = directions
+            .iter()
+            .zip(V::weights())
+            .map(|(c, w)| equilibrium(*w, rho, c.dot(&u), u.dot(&u), V::c_sqr()))
+            .collect();
+        let alpha = T::of(entropic_stabilizer::<V, f64>(&f, &f_eq));
+        let omega = alpha * beta;
+        let forcing = T::one() - half * omega;
+        for (q_i, o) in out.iter_mut().enumerate() {
+            *o = node.f[q_i] + omega * (node.f_eq[q_i] - node.f[q_i]) + forcing * node.source[q_i];
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::vector;
+
+    fn node_state(u: Vec3, perturbation: f32) -> (Vec<f32>, Vec<f32>) {
+        let f_eq: Vec<f32> = gen_d3q27_directions::<f32>()
+            .iter()
+            .zip(D3Q27_W.iter())
+            .map(|(c, w)| equilibrium(*w as f32, 1.0, c.dot(&u), u.dot(&u), D3Q27::c_sqr() as f32))
+            .collect();
+        // Odd and even populations pushed apart, mass is kept
+        let f = f_eq
+            .iter()
+            .enumerate()
+            .map(|(q_i, q)| q * (1.0 + if q_i % 2 == 0 { perturbation } else { -perturbation }))
+            .collect();
+        (f, f_eq)
+    }
+
+    #[test]
+    fn stabilizer_near_equilibrium() {
+        let (f, f_eq) = node_state(vector![0.08, -0.05, 0.03], 1e-3);
+        let alpha = entropic_stabilizer::<D3Q27, _>(&f, &f_eq);
+        assert!((alpha - 2.0).abs() < 1e-2, "alpha: {}", alpha);
+    }
+
+    #[test]
+    fn h_does_not_grow() {
+        let (f, f_eq) = node_state(vector![0.08, -0.05, 0.03], 0.6);
+        let alpha = entropic_stabilizer::<D3Q27, _>(&f, &f_eq);
+        assert!((alpha - 2.0).abs() > 0.1);
+
+        // The mirror state has the entropy of the pre-collision state
+        let to_f64 = |f: &[f32]| f.iter().map(|q| q.to_f64()).collect::<Vec<_>>();
+        let mirror: Vec<f32> = f.iter().zip(&f_eq).map(|(q, q_eq)| q + alpha * (q_eq - q)).collect();
+        let h = h_function::<D3Q27>(&to_f64(&f));
+        assert!((h_function::<D3Q27>(&to_f64(&mirror)) - h).abs() < 1e-6);
+
+        let source = vec![0.0; 27];
+        let node = CollisionNode {
+            rho: f.iter().sum(),
+            u: Vec3::zero(),
+            force: Vec3::zero(),
+            f: &f,
+            f_eq: &f_eq,
+            source: &source,
+            omega: None,
+        };
+        let mut out = vec![0.0; 27];
+        CollisionOperator::<D3Q27>::collide(&Entropic { omega: 1.95 }, &node, &mut out);
+
+        assert!(h_function::<D3Q27>(&to_f64(&out)) <= h);
+        assert!(out.iter().all(|q| *q > 0.0));
+    }
+
+    #[test]
+    fn stabilizer_with_negative_equilibrium() {
+        // A node at rest relaxing towards u = (0.5, 0.3, 0), where f_eq of (0, -1, 0)
+        // is negative and only steps below 1 keep the populations positive
+        let f: Vec<f32> = D3Q27_W.iter().map(|w| *w as f32).collect();
+        let (f_eq, _) = node_state(vector![0.5, 0.3, 0.0], 0.0);
+        assert!(f_eq.iter().any(|q| *q < 0.0));
+        let alpha = entropic_stabilizer::<D3Q27, _>(&f, &f_eq);
+        assert!(alpha > 0.0 && alpha < 1.0, "alpha: {}", alpha);
+        assert!(f.iter().zip(&f_eq).all(|(q, q_eq)| q + alpha * (q_eq - q) > 0.0));
+    }
+}
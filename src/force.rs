@@ -0,0 +1,28 @@
+use crate::*;
+
+/// Body force per unit volume acting on the fluid, see `Solver::set_body_force`
+pub enum BodyForce<T = f32> {
+    /// The same force at every node, e.g. gravity or a pressure gradient
+    Uniform(Vec3<T>),
+
+    /// A force per node
+    Field(VelArray<T>),
+}
+
+impl<T: NumTrait> BodyForce<T> {
+    pub fn at(&self, coord: &Coord<3>) -> Vec3<T> {
+        match self {
+            BodyForce::Uniform(force) => *force,
+            BodyForce::Field(field) => field.get(coord),
+        }
+    }
+}
+
+/// Guo forcing source for a single population, before the collision operator
+/// scales it by `1 - omega / 2`. `c` is the lattice velocity, `u` the half force
+/// shifted velocity from `Solver::moments`.
+pub fn guo_source<T: NumTrait>(w_i: T, c: &Vec3<T>, u: &Vec3<T>, force: &Vec3<T>, c_sqr: T) -> T {
+    let c_u = c.dot(u);
+    let term = (c - u) / c_sqr + c * (c_u / (c_sqr * c_sqr));
+    w_i * term.dot(force)
+}
@@ -0,0 +1,370 @@
+use crate::*;
+use nalgebra::vector;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Triangle {
+    pub vertices: [Vec3<f64>; 3],
+}
+
+/// Triangle soup read from STL or OBJ files
+#[derive(Clone, Debug, Default)]
+pub struct TriangleMesh {
+    pub triangles: Vec<Triangle>,
+}
+
+/// Node counts after voxelizing a mesh into a solver, see `Solver::add_geometry`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VoxelizationReport {
+    /// Nodes inside the mesh
+    pub solid_nodes: usize,
+
+    /// Nodes of the domain that are not solid
+    pub fluid_nodes: usize,
+
+    /// Non solid nodes with at least one solid neighbor along a lattice link
+    pub wetted_nodes: usize,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn parse_vertex<'a>(mut tokens: impl Iterator<Item = &'a str>, line: usize) -> Result<Vec3<f64>> {
+    let mut v = Vec3::zero();
+    for d in 0..3 {
+        let token = tokens
+            .next()
+            .ok_or_else(|| invalid(format!("line {}: expected three coordinates", line)))?;
+        v[d] = token
+            .parse()
+            .map_err(|_| invalid(format!("line {}: bad coordinate {}", line, token)))?;
+    }
+    Ok(v)
+}
+
+impl TriangleMesh {
+    /// Read an ASCII or binary STL file
+    pub fn read_stl<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse_stl(&std::fs::read(path)?)
+    }
+
+    /// Read a Wavefront OBJ file, polygons are triangulated as fans
+    pub fn read_obj<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse_obj(&std::fs::read_to_string(path)?)
+    }
+
+    /// Binary STL is recognized by its size, a header of 80 bytes and a triangle
+    /// count followed by 50 bytes per triangle. Trailing bytes are allowed unless
+    /// the file starts with `solid` like ASCII STL. Anything else is parsed as ASCII.
+    pub fn parse_stl(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() >= 84 {
+            let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+            let size = 84 + 50 * count;
+            if bytes.len() == size || (bytes.len() > size && !bytes.starts_with(b"solid")) {
+                return Ok(Self::parse_binary_stl(&bytes[84..], count));
+            }
+        }
+        let text = std::str::from_utf8(bytes).map_err(|_| invalid("STL is neither binary nor ASCII".to_string()))?;
+        Self::parse_ascii_stl(text)
+    }
+
+    fn parse_binary_stl(bytes: &[u8], count: usize) -> Self {
+        let read_f32 = |offset: usize| {
+            f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as f64
+        };
+
+        let mut triangles = Vec::with_capacity(count);
+        for t in 0..count {
+            // Skip the 12 byte normal, the attribute count trails the vertices
+            let start = 50 * t + 12;
+            let mut vertices = [Vec3::zero(); 3];
+            for (v, vertex) in vertices.iter_mut().enumerate() {
+                for d in 0..3 {
+                    vertex[d] = read_f32(start + 12 * v + 4 * d);
+                }
+            }
+            triangles.push(Triangle { vertices });
+        }
+        TriangleMesh { triangles }
+    }
+
+    fn parse_ascii_stl(text: &str) -> Result<Self> {
+        let mut triangles = Vec::new();
+        let mut vertices = Vec::with_capacity(3);
+        for (i, line) in text.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("vertex") => vertices.push(parse_vertex(tokens, i + 1)?),
+                Some("endloop") => {
+                    if vertices.len() != 3 {
+                        return Err(invalid(format!("line {}: facet without three vertices", i + 1)));
+                    }
+                    triangles.push(Triangle {
+                        vertices: [vertices[0], vertices[1], vertices[2]],
+                    });
+                    vertices.clear();
+                }
+                _ => {}
+            }
+        }
+        Ok(TriangleMesh { triangles })
+    }
+
+    pub fn parse_obj(text: &str) -> Result<Self> {
+        let mut positions = Vec::new();
+        let mut triangles = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => positions.push(parse_vertex(tokens, i + 1)?),
+                Some("f") => {
+                    // Indices are one based, negative ones count back from the last vertex,
+                    // texture and normal indices after a slash are ignored
+                    let mut face = Vec::new();
+                    for token in tokens {
+                        let index: i64 = token
+                            .split('/')
+                            .next()
+                            .and_then(|s| s.parse().ok())
+                            .ok_or_else(|| invalid(format!("line {}: bad face index {}", i + 1, token)))?;
+                        let resolved = if index < 0 { positions.len() as i64 + index } else { index - 1 };
+                        let vertex = usize::try_from(resolved)
+                            .ok()
+                            .and_then(|r| positions.get(r))
+                            .ok_or_else(|| invalid(format!("line {}: face index {} out of range", i + 1, index)))?;
+                        face.push(*vertex);
+                    }
+                    for k in 1..face.len().saturating_sub(1) {
+                        triangles.push(Triangle {
+                            vertices: [face[0], face[k], face[k + 1]],
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(TriangleMesh { triangles })
+    }
+
+    /// Smallest and largest vertex coordinates
+    pub fn bounds(&self) -> (Vec3<f64>, Vec3<f64>) {
+        let mut min = Vec3::repeat(f64::MAX);
+        let mut max = Vec3::repeat(f64::MIN);
+        for triangle in &self.triangles {
+            for v in &triangle.vertices {
+                min = min.inf(v);
+                max = max.sup(v);
+            }
+        }
+        (min, max)
+    }
+
+    /// Map every vertex `p` to `p * scale + translation`, lattice units are node spacings
+    pub fn transform(&mut self, scale: f64, translation: Vec3<f64>) {
+        for triangle in &mut self.triangles {
+            for v in &mut triangle.vertices {
+                *v = *v * scale + translation;
+            }
+        }
+    }
+
+    /// Uniformly scale and translate the mesh so it is centered in `aabb` and its
+    /// largest extent spans it. An empty mesh or a single point is left as is.
+    pub fn fit_to_box(&mut self, aabb: &AABB<3>) {
+        let (min, max) = self.bounds();
+        let target_min: Vec3<f64> = aabb.column(0).into_owned().cast::<f64>();
+        let target_size: Vec3<f64> = (aabb.column(1) - aabb.column(0)).cast::<f64>();
+        let size = max - min;
+        let mut scale = f64::MAX;
+        for d in 0..3 {
+            if size[d] > 0.0 {
+                scale = scale.min(target_size[d] / size[d]);
+            }
+        }
+        if scale == f64::MAX {
+            return;
+        }
+        let translation = target_min + (target_size - size * scale) * 0.5 - min * scale;
+        self.transform(scale, translation);
+    }
+
+    /// Nodes of `grid_dimensions` inside the mesh.
+    ///
+    /// A ray is cast along x through every y-z node line, and nodes after an odd
+    /// number of surface crossings are inside. The rays are nudged off the
+    /// node lines so they do not graze mesh edges or vertices. Triangles are
+    /// binned by the y lines they span, and skipped where their z extent misses
+    /// the ray.
+    pub fn voxelize(&self, grid_dimensions: &AABB<3>) -> Vec<Coord<3>> {
+        const NUDGE_Y: f64 = 1.0e-4 * std::f64::consts::SQRT_2;
+        const NUDGE_Z: f64 = 1.0e-4 * std::f64::consts::E;
+
+        let (y_min, y_max) = (grid_dimensions[(1, 0)], grid_dimensions[(1, 1)]);
+        let mut bins = vec![Vec::new(); (y_max - y_min + 1).max(0) as usize];
+        for triangle in &self.triangles {
+            let (lo, hi) = extent(triangle, 1);
+            let first = ((lo - NUDGE_Y).ceil() as i32).max(y_min);
+            let last = ((hi - NUDGE_Y).floor() as i32).min(y_max);
+            for y in first..=last {
+                bins[(y - y_min) as usize].push(triangle);
+            }
+        }
+
+        let mut result = Vec::new();
+        let mut crossings = Vec::new();
+        for (y, bin) in (y_min..=y_max).zip(&bins) {
+            for z in grid_dimensions[(2, 0)]..=grid_dimensions[(2, 1)] {
+                let ray_y = y as f64 + NUDGE_Y;
+                let ray_z = z as f64 + NUDGE_Z;
+                crossings.clear();
+                for triangle in bin {
+                    let (lo, hi) = extent(triangle, 2);
+                    if ray_z < lo || ray_z > hi {
+                        continue;
+                    }
+                    if let Some(x) = ray_crossing(triangle, ray_y, ray_z) {
+                        crossings.push(x);
+                    }
+                }
+                crossings.sort_by(|a, b| a.total_cmp(b));
+
+                for pair in crossings.chunks_exact(2) {
+                    let start = (pair[0].ceil() as i32).max(grid_dimensions[(0, 0)]);
+                    let end = (pair[1].floor() as i32).min(grid_dimensions[(0, 1)]);
+                    for x in start..=end {
+                        result.push(vector![x, y, z]);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Smallest and largest vertex coordinate of `triangle` along `axis`
+fn extent(triangle: &Triangle, axis: usize) -> (f64, f64) {
+    let values = triangle.vertices.map(|v| v[axis]);
+    (values[0].min(values[1]).min(values[2]), values[0].max(values[1]).max(values[2]))
+}
+
+/// x where the line `(y, z) = (ray_y, ray_z)` pierces the triangle, if it does
+fn ray_crossing(triangle: &Triangle, ray_y: f64, ray_z: f64) -> Option<f64> {
+    let [a, b, c] = triangle.vertices;
+    // Barycentric coordinates of the ray in the y-z projection
+    let det = (b[1] - a[1]) * (c[2] - a[2]) - (c[1] - a[1]) * (b[2] - a[2]);
+    if det.abs() < f64::EPSILON {
+        return None;
+    }
+    let s = ((ray_y - a[1]) * (c[2] - a[2]) - (c[1] - a[1]) * (ray_z - a[2])) / det;
+    let t = ((b[1] - a[1]) * (ray_z - a[2]) - (ray_y - a[1]) * (b[2] - a[2])) / det;
+    if s < 0.0 || t < 0.0 || s + t > 1.0 {
+        return None;
+    }
+    Some(a[0] + s * (b[0] - a[0]) + t * (c[0] - a[0]))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::matrix;
+
+    /// Axis aligned cube as 12 triangles
+    fn cube(min: f64, max: f64) -> TriangleMesh {
+        let corner = |i: usize| -> Vec3<f64> {
+            vector![
+                if i & 1 == 0 { min } else { max },
+                if i & 2 == 0 { min } else { max },
+                if i & 4 == 0 { min } else { max }
+            ]
+        };
+        let quads = [[0, 1, 3, 2], [4, 6, 7, 5], [0, 4, 5, 1], [2, 3, 7, 6], [0, 2, 6, 4], [1, 5, 7, 3]];
+        let mut triangles = Vec::new();
+        for q in quads {
+            triangles.push(Triangle {
+                vertices: [corner(q[0]), corner(q[1]), corner(q[2])],
+            });
+            triangles.push(Triangle {
+                vertices: [corner(q[0]), corner(q[2]), corner(q[3])],
+            });
+        }
+        TriangleMesh { triangles }
+    }
+
+    fn to_ascii_stl(mesh: &TriangleMesh) -> String {
+        let mut result = String::from("solid cube\n");
+        for t in &mesh.triangles {
+            result += "  facet normal 0 0 0\n    outer loop\n";
+            for v in &t.vertices {
+                result += &format!("      vertex {} {} {}\n", v[0], v[1], v[2]);
+            }
+            result += "    endloop\n  endfacet\n";
+        }
+        result + "endsolid cube\n"
+    }
+
+    fn to_binary_stl(mesh: &TriangleMesh) -> Vec<u8> {
+        let mut result = vec![0u8; 80];
+        result.extend((mesh.triangles.len() as u32).to_le_bytes());
+        for t in &mesh.triangles {
+            result.extend([0u8; 12]);
+            for v in &t.vertices {
+                for d in 0..3 {
+                    result.extend((v[d] as f32).to_le_bytes());
+                }
+            }
+            result.extend([0u8; 2]);
+        }
+        result
+    }
+
+    #[test]
+    fn stl_formats() {
+        let mesh = cube(1.5, 5.5);
+        let ascii = TriangleMesh::parse_stl(to_ascii_stl(&mesh).as_bytes()).unwrap();
+        let binary = TriangleMesh::parse_stl(&to_binary_stl(&mesh)).unwrap();
+        assert_eq!(ascii.triangles, mesh.triangles);
+        assert_eq!(binary.triangles, mesh.triangles);
+
+        // Trailing bytes after the triangles, but not after an ASCII looking header
+        let mut padded = to_binary_stl(&mesh);
+        padded.extend([0u8; 16]);
+        assert_eq!(TriangleMesh::parse_stl(&padded).unwrap().triangles, mesh.triangles);
+        padded[..5].copy_from_slice(b"solid");
+        assert!(TriangleMesh::parse_stl(&padded).is_err());
+    }
+
+    #[test]
+    fn obj_faces() {
+        let text = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1/1/1 2/2/1 3/3/1 4/4/1\nf -4 -3 -1\n";
+        let mesh = TriangleMesh::parse_obj(text).unwrap();
+        assert_eq!(mesh.triangles.len(), 3);
+        assert_eq!(mesh.triangles[1].vertices[2], vector![0.0, 1.0, 0.0]);
+        assert!(TriangleMesh::parse_obj("v 0 0 0\nf 1 2 3\n").is_err());
+    }
+
+    #[test]
+    fn voxelize_cube() {
+        let grid = matrix![0, 7; 0, 7; 0, 7];
+        let nodes = cube(1.5, 5.5).voxelize(&grid);
+        assert_eq!(nodes.len(), 4 * 4 * 4);
+        assert!(nodes.iter().all(|n| (2..=5).contains(&n[0]) && (2..=5).contains(&n[1])));
+        // Partly outside the grid
+        assert_eq!(cube(-2.5, 3.5).voxelize(&grid).len(), 4 * 4 * 4);
+
+        let mut mesh = cube(-1.0, 1.0);
+        mesh.fit_to_box(&matrix![1, 6; 1, 6; 1, 6]);
+        assert_eq!(mesh.bounds(), (Vec3::repeat(1.0), Vec3::repeat(6.0)));
+
+        let mut empty = TriangleMesh::default();
+        empty.fit_to_box(&matrix![1, 6; 1, 6; 1, 6]);
+        assert!(empty.triangles.is_empty());
+        let point = Triangle {
+            vertices: [Vec3::repeat(2.0); 3],
+        };
+        let mut degenerate = TriangleMesh { triangles: vec![point] };
+        degenerate.fit_to_box(&matrix![1, 6; 1, 6; 1, 6]);
+        assert_eq!(degenerate.triangles, vec![point]);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::*;
+
+/// Marks a solid node, which has no position in the list
+const SOLID: u32 = u32::MAX;
+
+/// Populations of the non solid nodes only, with indirect addressing, see
+/// `Solver::set_indirect`. Each link of each node has the index into `buffer` it
+/// pulls from, the node's own opposite population where a wall cuts the link.
+#[derive(Clone)]
+pub struct FluidNodes<T = f32> {
+    grid_dimensions: AABB<3>,
+    q_count: usize,
+    nodes: Vec<Coord<3>>,
+    /// Position in `nodes` of each node of the grid, or `SOLID`
+    positions: Vec<u32>,
+    /// Source of population q of each node, node by node
+    pull: Vec<u32>,
+    pub buffer: Vec<T>,
+}
+
+impl<T: NumTrait> FluidNodes<T> {
+    /// List the non solid nodes of `node_types` and their pull indices for the
+    /// links of `V`, wrapping around the `periodic` axes
+    pub fn new<V: VelocitySet>(node_types: &NodeTypeArray, periodic: &[bool; 3]) -> Self {
+        let grid_dimensions = *node_types.dimensions();
+        let mut positions = vec![SOLID; node_types.size()];
+        let mut nodes = Vec::new();
+        for (index, coord) in coord_iter(grid_dimensions).enumerate() {
+            if node_types.get(&coord) != NodeType::Solid {
+                positions[index] = nodes.len() as u32;
+                nodes.push(coord);
+            }
+        }
+
+        let offsets = V::gen_offsets();
+        let mut pull = Vec::with_capacity(nodes.len() * V::Q);
+        for (position, coord) in nodes.iter().enumerate() {
+            for (q_i, offset) in offsets.iter().enumerate() {
+                let neighbor = periodic_wrap(&grid_dimensions, &(coord - offset), periodic);
+                let source = match box_contains_coord(&grid_dimensions, &neighbor) {
+                    true => positions[coord_to_linear_in_box(&neighbor, &grid_dimensions)],
+                    false => SOLID,
+                };
+                pull.push(match source {
+                    SOLID => (position * V::Q + V::opposites()[q_i]) as u32,
+                    source => source * V::Q as u32 + q_i as u32,
+                });
+            }
+        }
+
+        FluidNodes {
+            grid_dimensions,
+            q_count: V::Q,
+            buffer: vec![T::zero(); nodes.len() * V::Q],
+            nodes,
+            positions,
+            pull,
+        }
+    }
+
+    pub fn grid_dimensions(&self) -> &AABB<3> {
+        &self.grid_dimensions
+    }
+
+    pub fn q_count(&self) -> usize {
+        self.q_count
+    }
+
+    /// Non solid nodes, in the order of `buffer`
+    pub fn nodes(&self) -> &[Coord<3>] {
+        &self.nodes
+    }
+
+    /// Position of `coord` in `nodes`
+    pub fn position(&self, coord: &Coord<3>) -> Option<usize> {
+        match self.positions[coord_to_linear_in_box(coord, &self.grid_dimensions)] {
+            SOLID => None,
+            position => Some(position as usize),
+        }
+    }
+
+    /// Index into `buffer` of population `q` of the node at `position`
+    pub fn index(&self, position: usize, q: usize) -> usize {
+        position * self.q_count + q
+    }
+
+    /// Index into `buffer` that population `q` of the node at `position` pulls from
+    pub fn pull_index(&self, position: usize, q: usize) -> usize {
+        self.pull[self.index(position, q)] as usize
+    }
+
+    /// Solid nodes read as zero
+    pub fn get_q(&self, coord: &Coord<3>, q: i32) -> T {
+        match self.position(coord) {
+            Some(position) => self.buffer[self.index(position, q as usize)],
+            None => T::zero(),
+        }
+    }
+
+    /// Writes to solid nodes are dropped
+    pub fn set_q(&mut self, coord: &Coord<3>, q: i32, value: T) {
+        if let Some(position) = self.position(coord) {
+            let index = self.index(position, q as usize);
+            self.buffer[index] = value;
+        }
+    }
+
+    /// Take the populations of the listed nodes from `dense`
+    pub fn from_dense(&mut self, dense: &Array4D<T>) {
+        for (position, coord) in self.nodes.iter().enumerate() {
+            for q in 0..self.q_count {
+                self.buffer[position * self.q_count + q] = dense.get_q(coord, q as i32);
+            }
+        }
+    }
+
+    /// Dense populations in `layout`, zero at the solid nodes
+    pub fn to_dense(&self, layout: Layout) -> Array4D<T> {
+        let q_bounds = nalgebra::matrix![0, self.q_count as i32 - 1];
+        #[allow(clippy::toplevel_ref_arg)]
+        let dimensions = nalgebra::stack![self.grid_dimensions; q_bounds];
+        let mut dense = Array4D::with_layout(dimensions, layout);
+        for (position, coord) in self.nodes.iter().enumerate() {
+            for q in 0..self.q_count {
+                dense.set_q(coord, q as i32, self.buffer[self.index(position, q)]);
+            }
+        }
+        dense
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::{matrix, vector};
+
+    #[test]
+    fn pull_indices() {
+        let grid_dimensions = matrix![0, 3; 0, 2; 0, 0];
+        let mut node_types = NodeTypeArray::new(grid_dimensions);
+        node_types.set(&vector![1, 1, 0], NodeType::Solid);
+        let nodes = FluidNodes::<f32>::new::<D2Q9>(&node_types, &[true, false, false]);
+        assert_eq!(nodes.nodes().len(), 11);
+        assert_eq!(nodes.position(&vector![1, 1, 0]), None);
+
+        let east = D2Q9::index_of([1, 0, 0]).unwrap();
+        let west = D2Q9::opposites()[east];
+        // From the fluid neighbor, around the periodic x axis, and bounced off the solid
+        let at = |coord| nodes.position(&coord).unwrap();
+        assert_eq!(nodes.pull_index(at(vector![3, 0, 0]), east), nodes.index(at(vector![2, 0, 0]), east));
+        assert_eq!(nodes.pull_index(at(vector![0, 2, 0]), east), nodes.index(at(vector![3, 2, 0]), east));
+        assert_eq!(nodes.pull_index(at(vector![2, 1, 0]), east), nodes.index(at(vector![2, 1, 0]), west));
+        let north = D2Q9::index_of([0, 1, 0]).unwrap();
+        let south = D2Q9::opposites()[north];
+        assert_eq!(nodes.pull_index(at(vector![0, 0, 0]), north), nodes.index(at(vector![0, 0, 0]), south));
+
+        let mut dense = Array4D::new(matrix![0, 3; 0, 2; 0, 0; 0, 8]);
+        dense.set_q(&vector![3, 2, 0], 4, 2.0);
+        dense.set_q(&vector![1, 1, 0], 4, 3.0);
+        let mut copy = nodes.clone();
+        copy.from_dense(&dense);
+        assert_eq!(copy.get_q(&vector![3, 2, 0], 4), 2.0);
+        let round_trip = copy.to_dense(Layout::Soa);
+        assert_eq!(round_trip.get_q(&vector![3, 2, 0], 4), 2.0);
+        assert_eq!(round_trip.get_q(&vector![1, 1, 0], 4), 0.0);
+    }
+}
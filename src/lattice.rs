@@ -1,70 +1,310 @@
 use crate::*;
 use nalgebra::vector;
 
-pub fn gen_d3q27_directions() -> [Vec3; 27] {
-    [
-        vector![0.0, 0.0, 0.0],
-        vector![1.0, 0.0, 0.0],
-        vector![-1.0, 0.0, 0.0],
-        vector![0.0, 1.0, 0.0],
-        vector![0.0, -1.0, 0.0],
-        vector![0.0, 0.0, 1.0],
-        vector![0.0, 0.0, -1.0],
-        vector![1.0, 1.0, 0.0],
-        vector![1.0, -1.0, 0.0],
-        vector![-1.0, 1.0, 0.0],
-        vector![-1.0, -1.0, 0.0],
-        vector![1.0, 0.0, 1.0],
-        vector![1.0, 0.0, -1.0],
-        vector![-1.0, 0.0, 1.0],
-        vector![-1.0, 0.0, -1.0],
-        vector![0.0, 1.0, 1.0],
-        vector![0.0, 1.0, -1.0],
-        vector![0.0, -1.0, 1.0],
-        vector![0.0, -1.0, -1.0],
-        vector![1.0, 1.0, 1.0],
-        vector![1.0, 1.0, -1.0],
-        vector![1.0, -1.0, 1.0],
-        vector![-1.0, 1.0, 1.0],
-        vector![1.0, -1.0, -1.0],
-        vector![-1.0, -1.0, 1.0],
-        vector![-1.0, 1.0, -1.0],
-        vector![-1.0, -1.0, -1.0],
-    ]
+/// A discrete velocity set `DdQq`.
+///
+/// Every set is described in three dimensions so that the solver can stay on
+/// `Coord<3>` and `Vec3`; two dimensional sets live in the x-y plane and
+/// should be run on a grid with a single z layer that is periodic in z.
+pub trait VelocitySet: Send + Sync + 'static {
+    /// Spatial dimension of the set
+    const D: usize;
+
+    /// Number of discrete velocities
+    const Q: usize;
+
+    /// Integer lattice offsets, one per velocity
+    fn offsets() -> &'static [[i32; 3]];
+
+    /// Index of the opposite velocity for each velocity
+    fn opposites() -> &'static [usize];
+
+    /// Quadrature weights, summing to one, in double precision so that either
+    /// solver precision rounds them once
+    fn weights() -> &'static [f64];
+
+    /// Squared lattice speed of sound
+    fn c_sqr() -> f64 {
+        1.0 / 3.0
+    }
+
+    fn gen_offsets() -> Vec<Coord<3>> {
+        Self::offsets()
+            .iter()
+            .map(|o| vector![o[0], o[1], o[2]])
+            .collect()
+    }
+
+    fn gen_directions<T: NumTrait>() -> Vec<Vec3<T>> {
+        Self::offsets()
+            .iter()
+            .map(|o| vector![T::of(o[0] as f64), T::of(o[1] as f64), T::of(o[2] as f64)])
+            .collect()
+    }
+
+    /// Find the index of the velocity with the given offset, if the set has one
+    fn index_of(offset: [i32; 3]) -> Option<usize> {
+        Self::offsets().iter().position(|o| *o == offset)
+    }
+}
+
+/// Second order equilibrium for a single population,
+/// `c_u` is `c_i . u` and `u_sqr` is `u . u`.
+pub fn equilibrium<T: NumTrait>(w_i: T, rho: T, c_u: T, u_sqr: T, c_sqr: T) -> T {
+    let two = T::of(2.0);
+    let t1 = c_u / c_sqr;
+    let t2 = (c_u * c_u) / (two * c_sqr * c_sqr);
+    let t3 = -u_sqr / (two * c_sqr);
+    w_i * rho * (T::one() + t1 + t2 + t3)
+}
+
+/// `equilibrium` minus `w_i`, for populations stored shifted by the weights, see
+/// `Solver::set_shifted`. Built from `rho - 1` so the deviation from rest keeps
+/// its precision.
+pub fn shifted_equilibrium<T: NumTrait>(w_i: T, rho: T, c_u: T, u_sqr: T, c_sqr: T) -> T {
+    let two = T::of(2.0);
+    let t1 = c_u / c_sqr;
+    let t2 = (c_u * c_u) / (two * c_sqr * c_sqr);
+    let t3 = -u_sqr / (two * c_sqr);
+    w_i * ((rho - T::one()) + rho * (t1 + t2 + t3))
+}
+
+/// Add `sign` times the weights to the populations `f`, e.g. to undo the shift of
+/// `Solver::set_shifted`
+pub fn shift_by_weights<V: VelocitySet, T: NumTrait>(f: &mut [T], sign: T) {
+    for (q, w_i) in f.iter_mut().zip(V::weights()) {
+        *q += sign * T::of(*w_i);
+    }
+}
+
+pub struct D2Q9;
+
+impl VelocitySet for D2Q9 {
+    const D: usize = 2;
+    const Q: usize = 9;
+
+    fn offsets() -> &'static [[i32; 3]] {
+        &D2Q9_OFFSETS
+    }
+
+    fn opposites() -> &'static [usize] {
+        &D2Q9_OPP
+    }
+
+    fn weights() -> &'static [f64] {
+        &D2Q9_W
+    }
+}
+
+/// D3Q7 shares its ordering with the first 7 velocities of D3Q27. It only
+/// resolves the first moment, enough for advection-diffusion but not for flow.
+pub struct D3Q7;
+
+impl VelocitySet for D3Q7 {
+    const D: usize = 3;
+    const Q: usize = 7;
+
+    fn offsets() -> &'static [[i32; 3]] {
+        &D3Q27_OFFSETS[0..7]
+    }
+
+    fn opposites() -> &'static [usize] {
+        &D3Q27_OPP[0..7]
+    }
+
+    fn weights() -> &'static [f64] {
+        &D3Q7_W
+    }
+
+    fn c_sqr() -> f64 {
+        0.25
+    }
+}
+
+pub struct D3Q15;
+
+impl VelocitySet for D3Q15 {
+    const D: usize = 3;
+    const Q: usize = 15;
+
+    fn offsets() -> &'static [[i32; 3]] {
+        &D3Q15_OFFSETS
+    }
+
+    fn opposites() -> &'static [usize] {
+        &D3Q15_OPP
+    }
+
+    fn weights() -> &'static [f64] {
+        &D3Q15_W
+    }
+}
+
+/// D3Q19 shares its ordering with the first 19 velocities of D3Q27
+pub struct D3Q19;
+
+impl VelocitySet for D3Q19 {
+    const D: usize = 3;
+    const Q: usize = 19;
+
+    fn offsets() -> &'static [[i32; 3]] {
+        &D3Q27_OFFSETS[0..19]
+    }
+
+    fn opposites() -> &'static [usize] {
+        &D3Q27_OPP[0..19]
+    }
+
+    fn weights() -> &'static [f64] {
+        &D3Q19_W
+    }
+}
+
+pub struct D3Q27;
+
+impl VelocitySet for D3Q27 {
+    const D: usize = 3;
+    const Q: usize = 27;
+
+    fn offsets() -> &'static [[i32; 3]] {
+        &D3Q27_OFFSETS
+    }
+
+    fn opposites() -> &'static [usize] {
+        &D3Q27_OPP
+    }
+
+    fn weights() -> &'static [f64] {
+        &D3Q27_W
+    }
+}
+
+pub static D2Q9_OFFSETS: [[i32; 3]; 9] = [
+    [0, 0, 0],   // 0
+    [1, 0, 0],   // 1
+    [-1, 0, 0],  // 2
+    [0, 1, 0],   // 3
+    [0, -1, 0],  // 4
+    [1, 1, 0],   // 5
+    [1, -1, 0],  // 6
+    [-1, 1, 0],  // 7
+    [-1, -1, 0], // 8
+];
+
+pub static D2Q9_OPP: [usize; 9] = [0, 2, 1, 4, 3, 8, 7, 6, 5];
+
+pub static D2Q9_W: [f64; 9] = [
+    4.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+];
+
+pub static D3Q7_W: [f64; 7] = [0.25, 0.125, 0.125, 0.125, 0.125, 0.125, 0.125];
+
+pub static D3Q15_OFFSETS: [[i32; 3]; 15] = [
+    [0, 0, 0],    // 0
+    [1, 0, 0],    // 1
+    [-1, 0, 0],   // 2
+    [0, 1, 0],    // 3
+    [0, -1, 0],   // 4
+    [0, 0, 1],    // 5
+    [0, 0, -1],   // 6
+    [1, 1, 1],    // 7
+    [1, 1, -1],   // 8
+    [1, -1, 1],   // 9
+    [-1, 1, 1],   // 10
+    [1, -1, -1],  // 11
+    [-1, -1, 1],  // 12
+    [-1, 1, -1],  // 13
+    [-1, -1, -1], // 14
+];
+
+pub static D3Q15_OPP: [usize; 15] = [0, 2, 1, 4, 3, 6, 5, 14, 12, 13, 11, 10, 8, 9, 7];
+
+pub static D3Q15_W: [f64; 15] = [
+    2.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 9.0,
+    1.0 / 72.0,
+    1.0 / 72.0,
+    1.0 / 72.0,
+    1.0 / 72.0,
+    1.0 / 72.0,
+    1.0 / 72.0,
+    1.0 / 72.0,
+    1.0 / 72.0,
+];
+
+pub static D3Q19_W: [f64; 19] = [
+    1.0 / 3.0,
+    1.0 / 18.0,
+    1.0 / 18.0,
+    1.0 / 18.0,
+    1.0 / 18.0,
+    1.0 / 18.0,
+    1.0 / 18.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+    1.0 / 36.0,
+];
+
+pub fn gen_d3q27_directions<T: NumTrait>() -> [Vec3<T>; 27] {
+    D3Q27_OFFSETS.map(|o| vector![T::of(o[0] as f64), T::of(o[1] as f64), T::of(o[2] as f64)])
 }
 
 pub fn gen_d3q27_offsets() -> [Coord<3>; 27] {
-    [
-        vector![0, 0, 0],   // 0
-        vector![1, 0, 0],   // 1
-        vector![-1, 0, 0],  // 2
-        vector![0, 1, 0],   // 3
-        vector![0, -1, 0],  // 4
-        vector![0, 0, 1],   // 5
-        vector![0, 0, -1],  // 6
-        vector![1, 1, 0],   // 7
-        vector![1, -1, 0],  // 8
-        vector![-1, 1, 0],  // 9
-        vector![-1, -1, 0], // 10
-        vector![1, 0, 1],   // 11
-        vector![1, 0, -1],  // 12
-        vector![-1, 0, 1],  // 13
-        vector![-1, 0, -1], // 14
-        vector![0, 1, 1],   // 15
-        vector![0, 1, -1],  // 16
-        vector![0, -1, 1],  // 17
-        vector![0, -1, -1], // 18
-        vector![1, 1, 1],   // 19
-        vector![1, 1, -1],  // 20
-        vector![1, -1, 1],  // 21
-        vector![-1, 1, 1],  // 22
-        vector![1, -1, -1], // 23
-        vector![-1, -1, 1], // 24
-        vector![-1, 1, -1], // 25
-        vector![-1, -1, -1], // 26
-    ]
+    D3Q27_OFFSETS.map(|o| vector![o[0], o[1], o[2]])
 }
 
+pub static D3Q27_OFFSETS: [[i32; 3]; 27] = [
+    [0, 0, 0],    // 0
+    [1, 0, 0],    // 1
+    [-1, 0, 0],   // 2
+    [0, 1, 0],    // 3
+    [0, -1, 0],   // 4
+    [0, 0, 1],    // 5
+    [0, 0, -1],   // 6
+    [1, 1, 0],    // 7
+    [1, -1, 0],   // 8
+    [-1, 1, 0],   // 9
+    [-1, -1, 0],  // 10
+    [1, 0, 1],    // 11
+    [1, 0, -1],   // 12
+    [-1, 0, 1],   // 13
+    [-1, 0, -1],  // 14
+    [0, 1, 1],    // 15
+    [0, 1, -1],   // 16
+    [0, -1, 1],   // 17
+    [0, -1, -1],  // 18
+    [1, 1, 1],    // 19
+    [1, 1, -1],   // 20
+    [1, -1, 1],   // 21
+    [-1, 1, 1],   // 22
+    [1, -1, -1],  // 23
+    [-1, -1, 1],  // 24
+    [-1, 1, -1],  // 25
+    [-1, -1, -1], // 26
+];
+
 pub static D3Q27_OPP: [usize; 27] = [
     0,
     2,
@@ -95,7 +335,7 @@ pub static D3Q27_OPP: [usize; 27] = [
     19,
 ];
 
-pub static D3Q27_W: [f32; 27] = [
+pub static D3Q27_W: [f64; 27] = [
     8.0 / 27.0,
     2.0 / 27.0,
     2.0 / 27.0,
@@ -128,7 +368,7 @@ pub static D3Q27_W: [f32; 27] = [
 #[cfg(test)]
 mod unit_tests {
     use super::*;
-    
+
     #[test]
     fn opposites() {
         let offsets = gen_d3q27_offsets();
@@ -143,18 +383,61 @@ mod unit_tests {
 
     #[test]
     fn weights() {
-        let s:f32 = D3Q27_W.iter().sum();
+        let s: f64 = D3Q27_W.iter().sum();
         assert!((1.0 - s).abs() < 0.00001);
     }
 
     #[test]
     fn dirs() {
         let mut sum = Vec3::zero();
-        for d in gen_d3q27_directions() {
+        for d in gen_d3q27_directions::<f32>() {
             sum += d;
         }
         for d in 0..3 {
             assert!(sum[d].abs() < 0.000001);
         }
     }
+
+    fn check_velocity_set<V: VelocitySet>() {
+        let offsets = V::gen_offsets();
+        let directions = V::gen_directions::<f64>();
+        assert_eq!(offsets.len(), V::Q);
+        assert_eq!(V::opposites().len(), V::Q);
+        assert_eq!(V::weights().len(), V::Q);
+
+        for q_i in 0..V::Q {
+            let r: Coord<3> = offsets[q_i] + offsets[V::opposites()[q_i]];
+            assert_eq!(r, Coord::<3>::zero());
+        }
+
+        let s: f64 = V::weights().iter().sum();
+        assert!((1.0 - s).abs() < 0.00001);
+
+        // Isotropy up to second order: sum w c_a = 0, sum w c_a c_b = c_s^2 delta_ab
+        for a in 0..V::D {
+            let mut first = 0.0;
+            for (dir, w) in directions.iter().zip(V::weights()) {
+                first += w * dir[a];
+            }
+            assert!(first.abs() < 0.00001);
+
+            for b in 0..V::D {
+                let mut second = 0.0;
+                for (dir, w) in directions.iter().zip(V::weights()) {
+                    second += w * dir[a] * dir[b];
+                }
+                let expected = if a == b { V::c_sqr() } else { 0.0 };
+                assert!((second - expected).abs() < 0.00001);
+            }
+        }
+    }
+
+    #[test]
+    fn velocity_sets() {
+        check_velocity_set::<D2Q9>();
+        check_velocity_set::<D3Q7>();
+        check_velocity_set::<D3Q15>();
+        check_velocity_set::<D3Q19>();
+        check_velocity_set::<D3Q27>();
+    }
 }
@@ -1,15 +1,49 @@
-#![feature(trait_alias)]
+#![feature(portable_simd)]
 
+mod boundary;
+mod central_moment;
+mod collision;
+mod component;
 mod coord_util;
+mod entropic;
+mod force;
+mod geometry;
+mod indirect;
 mod lattice;
+mod multiphase;
+mod obstacle;
+mod parallel;
+mod refinement;
 mod run;
+mod scalar;
+mod simd;
 mod solver;
+mod sparse;
+mod thermal;
+mod turbulence;
 mod array4d;
 
+pub use boundary::*;
+pub use central_moment::*;
+pub use collision::*;
+pub use component::*;
 pub use coord_util::*;
+pub use entropic::*;
+pub use force::*;
+pub use geometry::*;
+pub use indirect::*;
 pub use lattice::*;
+pub use multiphase::*;
+pub use obstacle::*;
+pub use parallel::*;
+pub use refinement::*;
 pub use run::*;
+pub use scalar::*;
+pub use simd::*;
 pub use solver::*;
+pub use sparse::*;
+pub use thermal::*;
+pub use turbulence::*;
 pub use array4d::*;
 
 
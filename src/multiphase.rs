@@ -0,0 +1,106 @@
+use crate::*;
+
+/// Effective mass `psi(rho)` of the Shan-Chen interaction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pseudopotential<T = f32> {
+    /// `rho_0 (1 - exp(-rho / rho_0))`, the original Shan-Chen form
+    Exponential { rho_0: T },
+
+    /// Chosen so that the fluid follows the Carnahan-Starling equation of state
+    /// with attraction `a`, repulsion `b` and temperature `t`, for an attractive `G`
+    CarnahanStarling { a: T, b: T, t: T },
+}
+
+/// Carnahan-Starling pressure `rho t (1 + x + x^2 - x^3) / (1 - x)^3 - a rho^2`, `x = b rho / 4`
+pub fn carnahan_starling_pressure<T: NumTrait>(rho: T, a: T, b: T, t: T) -> T {
+    let x = b * rho / T::of(4.0);
+    rho * t * (T::one() + x + x * x - x * x * x) / (T::one() - x).powi(3) - a * rho * rho
+}
+
+/// Shan-Chen pseudopotential multiphase model, see `Solver::set_shan_chen`.
+///
+/// Each fluid node feels `-G psi(x) sum_i w_i psi(x + c_i) c_i` from its fluid
+/// neighbors and `-G_wall psi(x) sum_i w_i s(x + c_i) c_i` from its solid ones,
+/// `s` being one for solid nodes and faces without periodicity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShanChen<T = f32> {
+    pub potential: Pseudopotential<T>,
+
+    /// Coupling constant, negative for attraction
+    pub g: T,
+
+    /// Adsorption strength of walls, positive for walls repelling the dense phase
+    pub g_wall: T,
+}
+
+impl<T: NumTrait> ShanChen<T> {
+    pub fn psi(&self, rho: T, c_sqr: T) -> T {
+        match self.potential {
+            Pseudopotential::Exponential { rho_0 } => rho_0 * (T::one() - (-rho / rho_0).exp()),
+            Pseudopotential::CarnahanStarling { a, b, t } => {
+                let excess = carnahan_starling_pressure(rho, a, b, t) - c_sqr * rho;
+                (T::of(2.0) * excess / (self.g * c_sqr)).max(T::zero()).sqrt()
+            }
+        }
+    }
+
+    /// Pressure of the model fluid, `c_s^2 rho + G c_s^2 psi^2 / 2`
+    pub fn pressure(&self, rho: T, c_sqr: T) -> T {
+        let psi = self.psi(rho, c_sqr);
+        c_sqr * rho + T::of(0.5) * self.g * c_sqr * psi * psi
+    }
+
+    /// Interaction force on every non solid node of `grid_dimensions` into `force`
+    pub fn interaction_force<V: VelocitySet>(
+        &self,
+        density: &Array3D<T>,
+        node_types: &NodeTypeArray,
+        grid_dimensions: &AABB<3>,
+        periodic: &[bool; 3],
+        force: &mut VelArray<T>,
+    ) {
+        let c_sqr = T::of(V::c_sqr());
+        let offsets = V::gen_offsets();
+        let directions = V::gen_directions::<T>();
+        for_each_node(grid_dimensions, &mut force.buffer, 1, || (), |_, coord, out| {
+            if node_types.get(&coord) == NodeType::Solid {
+                return;
+            }
+            let mut fluid = Vec3::zero();
+            let mut wall = Vec3::zero();
+            for ((offset, c), w_i) in offsets.iter().zip(&directions).zip(V::weights()) {
+                let neighbor = periodic_wrap(grid_dimensions, &(coord + offset), periodic);
+                if box_contains_coord(grid_dimensions, &neighbor) && node_types.get(&neighbor) != NodeType::Solid {
+                    fluid += c * (T::of(*w_i) * self.psi(density.get(&neighbor), c_sqr));
+                } else {
+                    wall += c * T::of(*w_i);
+                }
+            }
+            let psi = self.psi(density.get(&coord), c_sqr);
+            out[0] = -(fluid * self.g + wall * self.g_wall) * psi;
+        });
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn carnahan_starling_equation_of_state() {
+        let c_sqr = 1.0_f32 / 3.0;
+        let model = ShanChen {
+            potential: Pseudopotential::CarnahanStarling {
+                a: 1.0,
+                b: 4.0,
+                t: 0.08,
+            },
+            g: -1.0,
+            g_wall: 0.0,
+        };
+        for rho in [0.05, 0.2, 0.4] {
+            let expected = carnahan_starling_pressure(rho, 1.0, 4.0, 0.08);
+            assert!((model.pressure(rho, c_sqr) - expected).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+
+/// Solid geometry placed inside the domain, see `Solver::add_obstacle`
+#[derive(Clone)]
+pub enum Obstacle {
+    /// Every node of the box
+    Block(AABB<3>),
+
+    /// Nodes within `radius` of `center`
+    Sphere { center: Vec3<f64>, radius: f64 },
+
+    /// Nodes within `radius` of the line through `center` along `axis`
+    Cylinder { center: Vec3<f64>, radius: f64, axis: usize },
+
+    /// Each node of the box is solid with probability `solid_fraction`,
+    /// `seed` keeps the packing reproducible
+    Porous {
+        aabb: AABB<3>,
+        solid_fraction: f64,
+        seed: u64,
+    },
+
+    /// An explicit list of solid nodes, e.g. from voxelized geometry
+    Nodes(Vec<Coord<3>>),
+}
+
+impl Obstacle {
+    /// Solid nodes of this obstacle that lie within `grid_dimensions`
+    pub fn solid_nodes(&self, grid_dimensions: &AABB<3>) -> Vec<Coord<3>> {
+        let position = |coord: &Coord<3>| -> Vec3<f64> { coord.cast::<f64>() };
+        let mut result: Vec<Coord<3>> = match self {
+            Obstacle::Block(aabb) => coord_iter(*aabb).collect(),
+            Obstacle::Sphere { center, radius } => coord_iter(*grid_dimensions)
+                .filter(|coord| (position(coord) - center).norm_squared() <= radius * radius)
+                .collect(),
+            Obstacle::Cylinder { center, radius, axis } => coord_iter(*grid_dimensions)
+                .filter(|coord| {
+                    let mut r = position(coord) - center;
+                    r[*axis] = 0.0;
+                    r.norm_squared() <= radius * radius
+                })
+                .collect(),
+            Obstacle::Porous {
+                aabb,
+                solid_fraction,
+                seed,
+            } => {
+                let mut rng = StdRng::seed_from_u64(*seed);
+                coord_iter(*aabb)
+                    .filter(|_| rng.gen::<f64>() < *solid_fraction)
+                    .collect()
+            }
+            Obstacle::Nodes(nodes) => nodes.clone(),
+        };
+        result.retain(|coord| box_contains_coord(grid_dimensions, coord));
+        result
+    }
+
+    /// This obstacle on a patch of twice the resolution whose first node sits at
+    /// `origin` of `grid_dimensions`, see `RefinedSolver::add_patch`. Fine nodes
+    /// between solid nodes of `Porous` and `Nodes` are solid too.
+    pub fn refined(&self, origin: &Coord<3>, grid_dimensions: &AABB<3>) -> Obstacle {
+        let offset = origin.cast::<f64>();
+        match self {
+            Obstacle::Block(aabb) => Obstacle::Block(AABB::from_fn(|d, side| 2 * (aabb[(d, side)] - origin[d]))),
+            Obstacle::Sphere { center, radius } => Obstacle::Sphere {
+                center: (center - offset) * 2.0,
+                radius: radius * 2.0,
+            },
+            Obstacle::Cylinder { center, radius, axis } => Obstacle::Cylinder {
+                center: (center - offset) * 2.0,
+                radius: radius * 2.0,
+                axis: *axis,
+            },
+            Obstacle::Porous { .. } | Obstacle::Nodes(_) => {
+                let solid: HashSet<Coord<3>> = self.solid_nodes(grid_dimensions).into_iter().collect();
+                let corners = AABB::from_columns(&[Coord::zeros(), Coord::repeat(1)]);
+                let mut nodes = Vec::new();
+                for coord in &solid {
+                    for corner in coord_iter(corners) {
+                        let between = AABB::from_columns(&[*coord, coord + corner]);
+                        if coord_iter(between).all(|node| solid.contains(&node)) {
+                            nodes.push((coord - origin) * 2 + corner);
+                        }
+                    }
+                }
+                Obstacle::Nodes(nodes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::{matrix, vector};
+
+    #[test]
+    fn shapes() {
+        let grid = matrix![0, 10; 0, 10; 0, 4];
+
+        let sphere = Obstacle::Sphere {
+            center: vector![5.0, 5.0, 2.0],
+            radius: 1.0,
+        };
+        assert_eq!(sphere.solid_nodes(&grid).len(), 7);
+
+        let cylinder = Obstacle::Cylinder {
+            center: vector![5.0, 5.0, 0.0],
+            radius: 1.0,
+            axis: 2,
+        };
+        assert_eq!(cylinder.solid_nodes(&grid).len(), 5 * 5);
+
+        // Clipped to the grid
+        let block = Obstacle::Block(matrix![8, 12; 0, 0; 0, 0]);
+        assert_eq!(block.solid_nodes(&grid).len(), 3);
+
+        let porous = |seed| Obstacle::Porous {
+            aabb: grid,
+            solid_fraction: 0.3,
+            seed,
+        };
+        assert_eq!(porous(7).solid_nodes(&grid), porous(7).solid_nodes(&grid));
+    }
+
+    #[test]
+    fn refined_shapes() {
+        let grid = matrix![0, 10; 0, 10; 0, 4];
+        let origin = vector![2, 2, 0];
+        let fine = matrix![0, 12; 0, 12; 0, 8];
+
+        let block = Obstacle::Block(matrix![3, 4; 3, 3; 0, 0]).refined(&origin, &grid);
+        assert_eq!(block.solid_nodes(&fine).len(), 3);
+        let nodes = Obstacle::Nodes(vec![vector![3, 3, 0], vector![4, 3, 0], vector![6, 6, 1]]).refined(&origin, &grid);
+        let mut solid = nodes.solid_nodes(&fine);
+        solid.sort_by_key(|coord| (coord[0], coord[1], coord[2]));
+        assert_eq!(solid, vec![vector![2, 2, 0], vector![3, 2, 0], vector![4, 2, 0], vector![8, 8, 2]]);
+
+        let sphere = Obstacle::Sphere {
+            center: vector![5.0, 5.0, 2.0],
+            radius: 1.0,
+        };
+        let refined = sphere.refined(&origin, &grid);
+        assert_eq!(refined.solid_nodes(&fine).len(), 33);
+        assert!(refined.solid_nodes(&fine).contains(&vector![6, 6, 4]));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::*;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+// The solver kernels visit the nodes of a grid through these helpers. Buffers
+// follow the layout of `coord_iter`, x being the slowest axis, so they split into
+// contiguous slabs of constant x. Each slab runs the same code in the serial and
+// the `parallel` build, and a node only writes its own values, so both builds
+// give bitwise identical results.
+
+/// Nodes in one slab of constant x
+fn slab_size(aabb: &AABB<3>) -> usize {
+    box_buffer_size(aabb) / (aabb[(0, 1)] - aabb[(0, 0)] + 1) as usize
+}
+
+/// Run `kernel` on each node of `aabb` with its `stride` values of `buffer`.
+/// `init` makes scratch state, once per slab.
+pub fn for_each_node<T, S, I, F>(aabb: &AABB<3>, buffer: &mut [T], stride: usize, init: I, kernel: F)
+where
+    T: Send,
+    I: Fn() -> S + Send + Sync,
+    F: Fn(&mut S, Coord<3>, &mut [T]) + Send + Sync,
+{
+    let slab = slab_size(aabb);
+    let run_slab = |(index, values): (usize, &mut [T])| {
+        let mut scratch = init();
+        for (i, node) in values.chunks_mut(stride).enumerate() {
+            kernel(&mut scratch, linear_to_coord_in_box(index * slab + i, aabb), node);
+        }
+    };
+    #[cfg(feature = "parallel")]
+    buffer.par_chunks_mut(stride * slab).enumerate().for_each(run_slab);
+    #[cfg(not(feature = "parallel"))]
+    buffer.chunks_mut(stride * slab).enumerate().for_each(run_slab);
+}
+
+/// `for_each_node` over the populations of `array` in either layout. With
+/// `Layout::Soa` the kernel gets a copy of the node's populations, written back
+/// afterwards.
+pub fn for_each_node_q<T, S, I, F>(array: &mut Array4D<T>, init: I, kernel: F)
+where
+    T: NumTrait,
+    I: Fn() -> S + Send + Sync,
+    F: Fn(&mut S, Coord<3>, &mut [T]) + Send + Sync,
+{
+    let aabb = array.grid_dimensions();
+    let q_count = array.q_count();
+    if array.layout() == Layout::Aos {
+        return for_each_node(&aabb, &mut array.buffer, q_count, init, kernel);
+    }
+    let mut buffer = std::mem::take(&mut array.buffer);
+    {
+        let values = Disjoint::new(&mut buffer);
+        let array = &*array;
+        for_each_coord(&aabb, || (init(), vec![T::zero(); q_count]), |(scratch, f), coord| {
+            let node = coord_to_linear_in_box(&coord, &aabb);
+            // SAFETY: a node only touches its own populations
+            for (q, value) in f.iter_mut().enumerate() {
+                *value = unsafe { values.get(array.buffer_index(node, q)) };
+            }
+            kernel(scratch, coord, f);
+            for (q, value) in f.iter().enumerate() {
+                unsafe { values.set(array.buffer_index(node, q), *value) };
+            }
+        });
+    }
+    array.buffer = buffer;
+}
+
+/// `for_each_node` over two buffers at once, `a` with `stride` values per node
+/// and `b` with one
+pub fn for_each_node_zip<A, B, S, I, F>(aabb: &AABB<3>, a: &mut [A], stride: usize, b: &mut [B], init: I, kernel: F)
+where
+    A: Send,
+    B: Send,
+    I: Fn() -> S + Send + Sync,
+    F: Fn(&mut S, Coord<3>, &mut [A], &mut B) + Send + Sync,
+{
+    let slab = slab_size(aabb);
+    let run_slab = |(index, (a_values, b_values)): (usize, (&mut [A], &mut [B]))| {
+        let mut scratch = init();
+        for (i, (a_node, b_node)) in a_values.chunks_mut(stride).zip(b_values).enumerate() {
+            kernel(&mut scratch, linear_to_coord_in_box(index * slab + i, aabb), a_node, b_node);
+        }
+    };
+    #[cfg(feature = "parallel")]
+    a.par_chunks_mut(stride * slab)
+        .zip(b.par_chunks_mut(slab))
+        .enumerate()
+        .for_each(run_slab);
+    #[cfg(not(feature = "parallel"))]
+    a.chunks_mut(stride * slab).zip(b.chunks_mut(slab)).enumerate().for_each(run_slab);
+}
+
+/// `kernel` of each of `nodes`, in order. For scattered nodes such as those of a
+/// boundary, that are written back afterwards.
+pub fn map_nodes<T, S, I, F>(nodes: &[Coord<3>], init: I, kernel: F) -> Vec<T>
+where
+    T: Send,
+    I: Fn() -> S + Send + Sync,
+    F: Fn(&mut S, &Coord<3>) -> T + Send + Sync,
+{
+    #[cfg(feature = "parallel")]
+    return nodes.par_iter().map_init(init, kernel).collect();
+    #[cfg(not(feature = "parallel"))]
+    {
+        let mut scratch = init();
+        nodes.iter().map(|coord| kernel(&mut scratch, coord)).collect()
+    }
+}
+
+/// Run `kernel` on each node of `aabb`, for kernels that write through `Disjoint`
+pub fn for_each_coord<S, I, F>(aabb: &AABB<3>, init: I, kernel: F)
+where
+    I: Fn() -> S + Send + Sync,
+    F: Fn(&mut S, Coord<3>) + Send + Sync,
+{
+    let slab = slab_size(aabb);
+    let run_slab = |index: usize| {
+        let mut scratch = init();
+        for i in index * slab..(index + 1) * slab {
+            kernel(&mut scratch, linear_to_coord_in_box(i, aabb));
+        }
+    };
+    let slabs = (aabb[(0, 1)] - aabb[(0, 0)] + 1) as usize;
+    #[cfg(feature = "parallel")]
+    (0..slabs).into_par_iter().for_each(run_slab);
+    #[cfg(not(feature = "parallel"))]
+    (0..slabs).for_each(run_slab);
+}
+
+/// Run `kernel` on each node of the allocated tiles of `sparse`, tile by tile,
+/// for kernels that write through `Disjoint`
+pub fn for_each_tile_coord<T, S, I, F>(sparse: &BlockSparse<T>, init: I, kernel: F)
+where
+    T: NumTrait,
+    I: Fn() -> S + Send + Sync,
+    F: Fn(&mut S, Coord<3>) + Send + Sync,
+{
+    let run_tile = |tile: usize| {
+        let mut scratch = init();
+        for coord in coord_iter(sparse.tile_aabb(tile)) {
+            kernel(&mut scratch, coord);
+        }
+    };
+    #[cfg(feature = "parallel")]
+    (0..sparse.tile_count()).into_par_iter().for_each(run_tile);
+    #[cfg(not(feature = "parallel"))]
+    (0..sparse.tile_count()).for_each(run_tile);
+}
+
+/// Run `kernel` on each of `nodes`, in chunks of `LIST_CHUNK`, for kernels that
+/// write through `Disjoint`
+pub fn for_each_listed_coord<S, I, F>(nodes: &[Coord<3>], init: I, kernel: F)
+where
+    I: Fn() -> S + Send + Sync,
+    F: Fn(&mut S, Coord<3>) + Send + Sync,
+{
+    let run_chunk = |chunk: &[Coord<3>]| {
+        let mut scratch = init();
+        for coord in chunk {
+            kernel(&mut scratch, *coord);
+        }
+    };
+    #[cfg(feature = "parallel")]
+    nodes.par_chunks(LIST_CHUNK).for_each(run_chunk);
+    #[cfg(not(feature = "parallel"))]
+    nodes.chunks(LIST_CHUNK).for_each(run_chunk);
+}
+
+/// Nodes per task of `for_each_listed_coord`
+const LIST_CHUNK: usize = 1024;
+
+/// Run `kernel` on groups of up to `group` consecutive nodes of `aabb`, as ranges
+/// of linear indices. Groups do not straddle slabs.
+pub fn for_each_node_group<S, I, F>(aabb: &AABB<3>, group: usize, init: I, kernel: F)
+where
+    I: Fn() -> S + Send + Sync,
+    F: Fn(&mut S, std::ops::Range<usize>) + Send + Sync,
+{
+    let slab = slab_size(aabb);
+    let run_slab = |index: usize| {
+        let mut scratch = init();
+        let end = (index + 1) * slab;
+        for start in (index * slab..end).step_by(group) {
+            kernel(&mut scratch, start..(start + group).min(end));
+        }
+    };
+    let slabs = (aabb[(0, 1)] - aabb[(0, 0)] + 1) as usize;
+    #[cfg(feature = "parallel")]
+    (0..slabs).into_par_iter().for_each(run_slab);
+    #[cfg(not(feature = "parallel"))]
+    (0..slabs).for_each(run_slab);
+}
+
+/// A buffer written from several nodes at once, each element being read and
+/// written by at most one node of a kernel. In place streaming writes to the
+/// neighbors, but every population slot still belongs to a single node.
+pub(crate) struct Disjoint<'a, T> {
+    buffer: *mut T,
+    len: usize,
+    marker: std::marker::PhantomData<&'a mut [T]>,
+}
+
+unsafe impl<T: Send> Send for Disjoint<'_, T> {}
+unsafe impl<T: Send> Sync for Disjoint<'_, T> {}
+
+impl<'a, T: Copy> Disjoint<'a, T> {
+    pub(crate) fn new(buffer: &'a mut [T]) -> Self {
+        Disjoint {
+            buffer: buffer.as_mut_ptr(),
+            len: buffer.len(),
+            marker: std::marker::PhantomData,
+        }
+    }
+
+    /// # Safety
+    /// No other node of the kernel may access element `index`
+    pub(crate) unsafe fn get(&self, index: usize) -> T {
+        assert!(index < self.len);
+        *self.buffer.add(index)
+    }
+
+    /// # Safety
+    /// No other node of the kernel may access element `index`
+    pub(crate) unsafe fn set(&self, index: usize, value: T) {
+        assert!(index < self.len);
+        *self.buffer.add(index) = value;
+    }
+
+    /// # Safety
+    /// No other node of the kernel may access elements `range`
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) unsafe fn slice(&self, range: std::ops::Range<usize>) -> &mut [T] {
+        assert!(range.start <= range.end && range.end <= self.len);
+        std::slice::from_raw_parts_mut(self.buffer.add(range.start), range.len())
+    }
+}
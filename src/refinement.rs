@@ -0,0 +1,400 @@
+use crate::*;
+
+/// Density, velocity and pre-collision non-equilibrium populations of a node, as
+/// exchanged between levels, see `Solver::node_state`
+#[derive(Clone)]
+pub(crate) struct NodeState<T = f32> {
+    pub(crate) rho: T,
+    pub(crate) u: Vec3<T>,
+    pub(crate) neq: Vec<T>,
+}
+
+impl<T: NumTrait> NodeState<T> {
+    /// Mean of `states`, `None` if there are none
+    fn mean<'a>(states: impl Iterator<Item = &'a NodeState<T>>) -> Option<NodeState<T>> {
+        let mut count = 0;
+        let mut sum: Option<NodeState<T>> = None;
+        for state in states {
+            count += 1;
+            match &mut sum {
+                Some(sum) => {
+                    sum.rho += state.rho;
+                    sum.u += state.u;
+                    sum.neq.iter_mut().zip(&state.neq).for_each(|(a, b)| *a += *b);
+                }
+                None => sum = Some(state.clone()),
+            }
+        }
+        sum.map(|mut sum| {
+            let scale = T::of(count as f64).recip();
+            sum.rho *= scale;
+            sum.u *= scale;
+            sum.neq.iter_mut().for_each(|q| *q *= scale);
+            sum
+        })
+    }
+}
+
+/// A solver with static grid refinement. Patches of twice the resolution cover
+/// boxes of the grid and may hold finer patches in turn, see `add_patch`.
+///
+/// Lattice velocities and densities are the same on all levels, so a patch
+/// relaxes at `tau_f = 2 tau_c - 1/2` and takes two steps per step of its parent.
+/// Interface nodes on the rim of a patch are rebuilt from the parent, interpolated
+/// in space and between the parent steps, and the parent nodes inside a patch from
+/// the patch. The pre-collision non-equilibrium part is rescaled by `tau_f / (2 tau_c)`
+/// on the way in and by its inverse on the way out (Dupuis and Chopard, 2003).
+pub struct RefinedSolver<V: VelocitySet = D3Q27, T: NumTrait = f32> {
+    solver: Solver<V, T>,
+    patches: Vec<Patch<V, T>>,
+    obstacles: Vec<Obstacle>,
+}
+
+/// A refined level covering `aabb` of its parent, see `RefinedSolver::add_patch`
+pub struct Patch<V: VelocitySet = D3Q27, T: NumTrait = f32> {
+    aabb: AABB<3>,
+    level: RefinedSolver<V, T>,
+    /// Non-equilibrium scale from the parent to the patch
+    scale: T,
+    /// Interface nodes of the patch, with the parent nodes they interpolate
+    interface: Vec<(Coord<3>, Vec<Coord<3>>)>,
+    /// Interface states at the start of the parent step
+    previous: Vec<Option<NodeState<T>>>,
+    /// Parent nodes that take the state of the patch
+    restricted: Vec<Coord<3>>,
+}
+
+impl<V: VelocitySet, T: NumTrait> RefinedSolver<V, T> {
+    /// `solver` as the coarsest level, initialize it before adding patches
+    pub fn new(solver: Solver<V, T>) -> Self {
+        RefinedSolver {
+            solver,
+            patches: Vec::new(),
+            obstacles: Vec::new(),
+        }
+    }
+
+    pub fn solver(&self) -> &Solver<V, T> {
+        &self.solver
+    }
+
+    pub fn solver_mut(&mut self) -> &mut Solver<V, T> {
+        &mut self.solver
+    }
+
+    pub fn patches(&self) -> &[Patch<V, T>] {
+        &self.patches
+    }
+
+    pub fn patch_mut(&mut self, index: usize) -> &mut Patch<V, T> {
+        &mut self.patches[index]
+    }
+
+    /// Add `obstacle` to this level and, refined, to its patches
+    pub fn add_obstacle(&mut self, obstacle: Obstacle) {
+        let grid_dimensions = *self.solver.node_types().dimensions();
+        for patch in &mut self.patches {
+            let origin = patch.aabb.column(0).into_owned();
+            patch.level.add_obstacle(obstacle.refined(&origin, &grid_dimensions));
+        }
+        self.solver.add_obstacle(obstacle.clone());
+        self.obstacles.push(obstacle);
+    }
+
+    /// Refine `aabb` of this level by a factor of two, returning the new level to
+    /// configure or refine further. The patch starts from the interpolated state of
+    /// this level and takes its obstacles and body force.
+    ///
+    /// Only `Bgk` collisions with `Split` propagation are supported, the rescaling
+    /// assumes a single relaxation time. A patch may not touch a wall or any other
+    /// boundary of the grid: along each axis it either lies strictly inside the
+    /// grid, with interfaces on both sides, or spans a periodic axis or one of a
+    /// single node. Obstacles may cross a patch.
+    pub fn add_patch(&mut self, aabb: AABB<3>) -> &mut RefinedSolver<V, T> {
+        let collision = self.solver.collision_operator();
+        assert!(collision.bgk_omega().is_some(), "refinement supports BGK collisions");
+        let tau = collision.omega().recip();
+        assert!(self.solver.propagation() == Propagation::Split, "refinement needs the split propagation");
+        let tau_fine = T::of(2.0) * tau - T::of(0.5);
+
+        let parent = *self.solver.node_types().dimensions();
+        let periodic = self.solver.periodic();
+        let mut grid_dimensions = AABB::zeros();
+        let mut interface_axes = [false; 3];
+        for d in 0..3 {
+            let extent = parent[(d, 1)] - parent[(d, 0)] + 1;
+            let spans = aabb[(d, 0)] == parent[(d, 0)] && aabb[(d, 1)] == parent[(d, 1)];
+            grid_dimensions[(d, 1)] = if extent == 1 {
+                0
+            } else if spans && periodic[d] {
+                2 * extent - 1
+            } else {
+                assert!(
+                    aabb[(d, 0)] > parent[(d, 0)] && aabb[(d, 1)] < parent[(d, 1)],
+                    "a patch must lie inside the grid or span a periodic axis"
+                );
+                assert!(aabb[(d, 1)] - aabb[(d, 0)] >= 2, "a patch needs nodes inside its interfaces");
+                interface_axes[d] = true;
+                2 * (aabb[(d, 1)] - aabb[(d, 0)])
+            };
+        }
+
+        // Interface nodes are fluid, their populations come from the parent
+        let mut boundaries = BoundaryRegistry::closed_box();
+        for face in Face::all() {
+            if interface_axes[face.axis()] {
+                boundaries.set_face(face, BoundaryType::Open);
+            }
+        }
+        for (axis, periodic) in periodic.iter().enumerate() {
+            if *periodic && !interface_axes[axis] {
+                boundaries.set_periodic(axis);
+            }
+        }
+        let mut solver = Solver::<V, T>::new(grid_dimensions, tau_fine.recip(), T::one(), T::zero());
+        solver.set_boundaries(boundaries);
+        // Lattice accelerations halve with the spacing
+        let origin = aabb.column(0).into_owned();
+        match self.solver.body_force() {
+            Some(BodyForce::Uniform(force)) => solver.set_body_force(BodyForce::Uniform(force * T::of(0.5))),
+            Some(BodyForce::Field(field)) => {
+                let mut fine = VelArray::new(grid_dimensions);
+                for coord in coord_iter(grid_dimensions) {
+                    let parent_coord = periodic_wrap(&parent, &(origin + coord / 2), &periodic);
+                    fine.set(&coord, field.get(&parent_coord) * T::of(0.5));
+                }
+                solver.set_body_force(BodyForce::Field(fine));
+            }
+            None => {}
+        }
+        let mut level = RefinedSolver::new(solver);
+        for obstacle in &self.obstacles {
+            level.add_obstacle(obstacle.refined(&origin, &parent));
+        }
+
+        let sources = |coord: &Coord<3>| -> Vec<Coord<3>> {
+            let around = AABB::from_fn(|d, side| coord[d] / 2 + (side as i32) * (coord[d] % 2));
+            coord_iter(around)
+                .map(|half| periodic_wrap(&parent, &(origin + half), &periodic))
+                .collect()
+        };
+        let mut patch = Patch {
+            aabb,
+            level,
+            scale: tau_fine / (T::of(2.0) * tau),
+            interface: Vec::new(),
+            previous: Vec::new(),
+            restricted: Vec::new(),
+        };
+        let on_rim = |coord: &Coord<3>, aabb: &AABB<3>| {
+            (0..3).any(|d| interface_axes[d] && (coord[d] == aabb[(d, 0)] || coord[d] == aabb[(d, 1)]))
+        };
+        for coord in coord_iter(grid_dimensions) {
+            let fine = &mut patch.level.solver;
+            if fine.node_types().get(&coord) != NodeType::Solid {
+                if let Some(state) = self.interpolate(&sources(&coord)) {
+                    fine.set_node_state(&coord, &state, patch.scale);
+                }
+            }
+            if on_rim(&coord, &grid_dimensions) {
+                patch.interface.push((coord, sources(&coord)));
+            }
+        }
+        patch.restricted = coord_iter(aabb).filter(|coord| !on_rim(coord, &aabb)).collect();
+        patch.previous = patch.interface_states(self);
+
+        self.patches.push(patch);
+        &mut self.patches.last_mut().unwrap().level
+    }
+
+    /// Mean state of the non solid nodes of `nodes`
+    fn interpolate(&self, nodes: &[Coord<3>]) -> Option<NodeState<T>> {
+        let states: Vec<NodeState<T>> = nodes
+            .iter()
+            .filter(|coord| self.solver.node_types().get(coord) != NodeType::Solid)
+            .map(|coord| self.solver.node_state(coord))
+            .collect();
+        NodeState::mean(states.iter())
+    }
+
+    /// Advance this level by one step and its patches by two of theirs
+    pub fn step(&mut self) {
+        self.advance(&[]);
+    }
+
+    /// `step`, returning the pre-collision states of the non solid nodes of `nodes`
+    fn advance(&mut self, nodes: &[Coord<3>]) -> Vec<Option<NodeState<T>>> {
+        self.solver.streaming();
+        self.solver.apply_bcs();
+        self.solver.moments();
+        let solver = &self.solver;
+        let states = nodes
+            .iter()
+            .map(|coord| (solver.node_types().get(coord) != NodeType::Solid).then(|| solver.node_state(coord)))
+            .collect();
+        let mut patches = std::mem::take(&mut self.patches);
+        let next: Vec<Vec<Option<NodeState<T>>>> = patches.iter().map(|patch| patch.interface_states(self)).collect();
+        self.solver.collision();
+
+        for (patch, next) in patches.iter_mut().zip(next) {
+            let middle: Vec<Option<NodeState<T>>> = patch
+                .previous
+                .iter()
+                .zip(&next)
+                .map(|(previous, next)| NodeState::mean(previous.iter().chain(next)))
+                .collect();
+            patch.level.advance(&[]);
+            patch.impose(&middle);
+            let fine: Vec<Coord<3>> = patch.restricted.iter().map(|coord| patch.fine_coord(coord)).collect();
+            let restricted = patch.level.advance(&fine);
+            patch.impose(&next);
+            patch.previous = next;
+            patch.restrict(&mut self.solver, &restricted);
+        }
+        self.patches = patches;
+        states
+    }
+}
+
+impl<V: VelocitySet, T: NumTrait> Patch<V, T> {
+    /// Box of the parent covered by this patch
+    pub fn aabb(&self) -> &AABB<3> {
+        &self.aabb
+    }
+
+    pub fn level(&self) -> &RefinedSolver<V, T> {
+        &self.level
+    }
+
+    pub fn level_mut(&mut self) -> &mut RefinedSolver<V, T> {
+        &mut self.level
+    }
+
+    /// Node of the patch at node `coord` of the parent
+    pub fn fine_coord(&self, coord: &Coord<3>) -> Coord<3> {
+        (coord - self.aabb.column(0)) * 2
+    }
+
+    /// State of the parent at each interface node
+    fn interface_states(&self, parent: &RefinedSolver<V, T>) -> Vec<Option<NodeState<T>>> {
+        self.interface.iter().map(|(_, sources)| parent.interpolate(sources)).collect()
+    }
+
+    fn impose(&mut self, states: &[Option<NodeState<T>>]) {
+        let solver = &mut self.level.solver;
+        for ((coord, _), state) in self.interface.iter().zip(states) {
+            if let Some(state) = state {
+                if solver.node_types().get(coord) != NodeType::Solid {
+                    solver.set_node_state(coord, state, self.scale);
+                }
+            }
+        }
+    }
+
+    /// Parent nodes inside the interfaces take `states` of the patch
+    fn restrict(&self, parent: &mut Solver<V, T>, states: &[Option<NodeState<T>>]) {
+        for (coord, state) in self.restricted.iter().zip(states) {
+            if let Some(state) = state {
+                if parent.node_types().get(coord) != NodeType::Solid {
+                    parent.set_node_state(coord, state, self.scale.recip());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::{matrix, vector};
+
+    #[test]
+    fn uniform_flow_crosses_interfaces() {
+        let u = vector![0.05, -0.02, 0.0];
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 11; 0, 9; 0, 0], 1.5, 1.0, 0.0);
+        for axis in 0..3 {
+            solver.set_periodic(axis, true);
+        }
+        let directions = D2Q9::gen_directions::<f32>();
+        for coord in coord_iter(matrix![0, 11; 0, 9; 0, 0]) {
+            for (q_i, w_i) in D2Q9::weights().iter().enumerate() {
+                let value = equilibrium(*w_i as f32, 1.0, directions[q_i].dot(&u), u.dot(&u), D2Q9::c_sqr() as f32);
+                solver.distributions.set_q(&coord, q_i as i32, value);
+            }
+        }
+        solver.moments();
+        let mut refined = RefinedSolver::new(solver);
+        refined.add_patch(matrix![3, 8; 2, 7; 0, 0]).add_patch(matrix![2, 7; 3, 8; 0, 0]);
+        for _ in 0..20 {
+            refined.step();
+        }
+
+        let mut level = &refined;
+        loop {
+            let solver = level.solver();
+            for coord in coord_iter(*solver.node_types().dimensions()) {
+                assert!((solver.density().get(&coord) - 1.0).abs() < 1e-5);
+                assert!((solver.velocity().get(&coord) - u).amax() < 1e-5, "{:?}", coord);
+            }
+            match level.patches().first() {
+                Some(patch) => level = patch.level(),
+                None => break,
+            }
+        }
+    }
+
+    /// Gravity driven channel with walls at 0.5 and 6.5 and two nested patches in
+    /// the middle, `tolerance` is relative to the peak velocity
+    fn check_refined_poiseuille(omega: f32, tolerance: f32) {
+        let g = 1e-5;
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 5; 0, 7; 0, 0], omega, 1.0, 0.0);
+        solver.set_periodic(0, true);
+        solver.set_periodic(2, true);
+        solver.set_body_force(BodyForce::Uniform(vector![g, 0.0, 0.0]));
+        solver.equilibrium_init();
+        solver.moments();
+        let mut refined = RefinedSolver::new(solver);
+        let patch = refined.add_patch(matrix![0, 5; 2, 5; 0, 0]);
+        assert_eq!(patch.solver().node_types().dimensions(), &matrix![0, 11; 0, 6; 0, 0]);
+        patch.add_patch(matrix![0, 11; 2, 4; 0, 0]);
+        for _ in 0..400 {
+            refined.step();
+        }
+
+        let nu = D2Q9::c_sqr() as f32 * (1.0 / omega - 0.5);
+        let u_max = g / (2.0 * nu) * 9.0;
+        // Position of each level's y = 0 on the coarse grid, and its spacing
+        let mut level = &refined;
+        let (mut y_0, mut spacing) = (0.0, 1.0);
+        loop {
+            let solver = level.solver();
+            for coord in coord_iter(*solver.node_types().dimensions()) {
+                if solver.node_types().get(&coord) == NodeType::Solid {
+                    continue;
+                }
+                let y = y_0 + spacing * coord[1] as f32;
+                let expected = g / (2.0 * nu) * (y - 0.5) * (6.5 - y);
+                let u = solver.velocity().get(&coord);
+                assert!((u[0] - expected).abs() < tolerance * u_max, "{} {:?}: {} {}", spacing, coord, u[0], expected);
+                assert!(u[1].abs() < 1e-3 * u_max);
+            }
+            let Some(patch) = level.patches().first() else { break };
+            y_0 += spacing * patch.aabb()[(1, 0)] as f32;
+            spacing *= 0.5;
+            level = patch.level();
+        }
+    }
+
+    #[test]
+    fn refined_poiseuille() {
+        // At this rate halfway bounce back puts the walls exactly at 0.5 and 6.5
+        check_refined_poiseuille(1.0 / (0.5 + (3.0_f32 / 16.0).sqrt()), 1e-2);
+    }
+
+    #[test]
+    fn refined_poiseuille_at_unit_rate() {
+        // The post-collision non-equilibrium part vanishes at omega = 1, the
+        // pre-collision one carries the shear across the interfaces
+        check_refined_poiseuille(1.0, 1e-2);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::*;
 
-pub fn run(solver: &mut Solver, n_it: usize, n_out: usize) {
+pub fn run<V: VelocitySet>(solver: &mut Solver<V>, n_it: usize, n_out: usize) {
     println!("Starting Run");
     let mut iter = 0;
 
@@ -11,18 +11,16 @@ pub fn run(solver: &mut Solver, n_it: usize, n_out: usize) {
     while iter < n_it {
         println!("  iter: {}", iter);
         let write_output = n_out > 0 && iter % n_out == 0;
-        println!("    streaming...");
-        solver.streaming();
-        println!("    moments...");
-        solver.moments();
-        println!("    collision...");
-        solver.collision();
-        println!("    apply_bcs...");
-        solver.apply_bcs();
+        solver.step();
 
         if write_output {
             println!("    writing snapshot {:06}", iter);
             solver.write_vtk(iter);
+            for face in Face::all() {
+                if let Some(nusselt) = solver.nusselt(face) {
+                    println!("    Nusselt number at {:?}: {}", face, nusselt);
+                }
+            }
         }
 
         iter += 1;
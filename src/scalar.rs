@@ -0,0 +1,154 @@
+use crate::*;
+
+/// Condition on the scalar at a face, see `ScalarField::set_boundary`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScalarBoundary<T = f32> {
+    /// Fixed value on the halfway wall, by anti bounce-back
+    Dirichlet(T),
+
+    /// Fixed flux into the domain per unit area, zero for an insulated face
+    Neumann(T),
+}
+
+/// Passive scalar such as a temperature or a species concentration, see
+/// `Solver::set_scalar`.
+///
+/// It lives on a D3Q7 lattice with the linear equilibrium `w_i C (1 + c_i . u / c_s^2)`,
+/// is advected by the flow velocity and diffuses with `D = c_s^2 (1 / omega - 1 / 2)`.
+/// Faces without a condition and obstacles are insulated.
+pub struct ScalarField<T = f32> {
+    pub name: String,
+    pub distributions: Array4D<T>,
+    distributions_buffer: Array4D<T>,
+    value: Array3D<T>,
+    omega: T,
+    boundaries: Vec<(Face, ScalarBoundary<T>)>,
+}
+
+impl<T: NumTrait> ScalarField<T> {
+    pub fn new(name: &str, grid_dimensions: AABB<3>, omega: T) -> Self {
+        let q_bounds = nalgebra::matrix![0, D3Q7::Q as i32 - 1];
+        #[allow(clippy::toplevel_ref_arg)]
+        let dimensions = nalgebra::stack![grid_dimensions; q_bounds];
+        ScalarField {
+            name: name.to_string(),
+            distributions: Array4D::new(dimensions),
+            distributions_buffer: Array4D::new(dimensions),
+            value: Array3D::new(grid_dimensions),
+            omega,
+            boundaries: Vec::new(),
+        }
+    }
+
+    pub fn omega(&self) -> T {
+        self.omega
+    }
+
+    pub fn diffusivity(&self) -> T {
+        T::of(D3Q7::c_sqr()) * (self.omega.recip() - T::of(0.5))
+    }
+
+    pub fn set_boundary(&mut self, face: Face, boundary: ScalarBoundary<T>) {
+        self.boundaries.retain(|(f, _)| *f != face);
+        self.boundaries.push((face, boundary));
+    }
+
+    pub fn boundary(&self, face: Face) -> ScalarBoundary<T> {
+        self.boundaries
+            .iter()
+            .find(|(f, _)| *f == face)
+            .map(|(_, boundary)| *boundary)
+            .unwrap_or(ScalarBoundary::Neumann(T::zero()))
+    }
+
+    /// Scalar of the last `moments`
+    pub fn value(&self) -> &Array3D<T> {
+        &self.value
+    }
+
+    /// Equilibrium at rest with the scalar given per node
+    pub fn init(&mut self, value: impl Fn(&Coord<3>) -> T) {
+        for coord in coord_iter(*self.value.dimensions()) {
+            let c = value(&coord);
+            for (q_i, w_i) in D3Q7::weights().iter().enumerate() {
+                self.distributions.set_q(&coord, q_i as i32, T::of(*w_i) * c);
+            }
+            self.value.set(&coord, c);
+        }
+    }
+
+    /// Pull streaming, links cut by a face follow the condition of that face,
+    /// links cut by an obstacle bounce back
+    pub fn stream(&mut self, node_types: &NodeTypeArray, periodic: &[bool; 3]) {
+        let grid_dimensions = *self.value.dimensions();
+        let offsets = D3Q7::gen_offsets();
+        let (two, c_sqr) = (T::of(2.0), T::of(D3Q7::c_sqr()));
+        let mut buffer = std::mem::take(&mut self.distributions_buffer);
+        for_each_node(&grid_dimensions, &mut buffer.buffer, D3Q7::Q, || (), |_, coord, out| {
+            if node_types.get(&coord) == NodeType::Solid {
+                return;
+            }
+            for (q_i, offset) in offsets.iter().enumerate() {
+                let neighbor = periodic_wrap(&grid_dimensions, &(coord - offset), periodic);
+                let in_domain = box_contains_coord(&grid_dimensions, &neighbor);
+                let q = if in_domain && node_types.get(&neighbor) != NodeType::Solid {
+                    self.distributions.get_q(&neighbor, q_i as i32)
+                } else {
+                    let reflected = self.distributions.get_q(&coord, D3Q7::opposites()[q_i] as i32);
+                    let w_i = T::of(D3Q7::weights()[q_i]);
+                    match self.cut_face(&neighbor, offset, periodic).map(|face| self.boundary(face)) {
+                        Some(ScalarBoundary::Dirichlet(value)) => two * w_i * value - reflected,
+                        Some(ScalarBoundary::Neumann(flux)) => reflected + two * w_i * flux / c_sqr,
+                        None => reflected,
+                    }
+                };
+                out[q_i] = q;
+            }
+        });
+        self.distributions_buffer = std::mem::replace(&mut self.distributions, buffer);
+    }
+
+    /// The face whose wall cuts the link pulling along `offset` from `neighbor`,
+    /// `None` for obstacles
+    fn cut_face(&self, neighbor: &Coord<3>, offset: &Coord<3>, periodic: &[bool; 3]) -> Option<Face> {
+        let grid_dimensions = self.value.dimensions();
+        let axis = offset.iamax();
+        if periodic[axis] {
+            return None;
+        }
+        let face = Face::all()
+            .into_iter()
+            .find(|face| face.axis() == axis && face.is_max() == (offset[axis] < 0))?;
+        let plane = grid_dimensions[(axis, face.is_max() as usize)];
+        let beyond = if face.is_max() { neighbor[axis] >= plane } else { neighbor[axis] <= plane };
+        beyond.then_some(face)
+    }
+
+    pub fn moments(&mut self, node_types: &NodeTypeArray) {
+        let grid_dimensions = *self.value.dimensions();
+        for_each_node(&grid_dimensions, &mut self.value.buffer, 1, || (), |_, coord, value| {
+            if node_types.get(&coord) == NodeType::Solid {
+                return;
+            }
+            value[0] = (0..D3Q7::Q).map(|q_i| self.distributions.get_q(&coord, q_i as i32)).sum();
+        });
+    }
+
+    /// BGK relaxation towards the equilibrium at `velocity`
+    pub fn collide(&mut self, velocity: &VelArray<T>, node_types: &NodeTypeArray) {
+        let directions = D3Q7::gen_directions::<T>();
+        let c_sqr = T::of(D3Q7::c_sqr());
+        let grid_dimensions = *self.value.dimensions();
+        for_each_node(&grid_dimensions, &mut self.distributions.buffer, D3Q7::Q, || (), |_, coord, g| {
+            if node_types.get(&coord) == NodeType::Solid {
+                return;
+            }
+            let u = velocity.get(&coord);
+            let value = self.value.get(&coord);
+            for ((q, c), w_i) in g.iter_mut().zip(&directions).zip(D3Q7::weights()) {
+                let q_eq = T::of(*w_i) * value * (T::one() + c.dot(&u) / c_sqr);
+                *q += self.omega * (q_eq - *q);
+            }
+        });
+    }
+}
@@ -0,0 +1,179 @@
+use crate::*;
+use std::ops::{Add, AddAssign, Index, Mul, Sub, SubAssign};
+use std::simd::prelude::*;
+
+// Kernels on groups of nodes for populations in `Layout::Soa`, where population q
+// of consecutive nodes is contiguous and a whole group loads at once. Used by
+// `Solver::moments` and `Solver::collision` after `Solver::set_simd`.
+
+/// Nodes per SIMD group
+pub const LANES: usize = 8;
+
+/// One value per node of a group
+pub type Lanes<T> = <T as NumTrait>::Lanes;
+
+/// The `Simd` operations the kernels use, for `NumTrait::Lanes`
+pub trait SimdLanes<T>:
+    Copy
+    + Send
+    + Sync
+    + PartialEq
+    + std::fmt::Debug
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + AddAssign
+    + SubAssign
+    + Index<usize, Output = T>
+{
+    fn splat(value: T) -> Self;
+
+    fn from_array(values: [T; LANES]) -> Self;
+
+    /// The first `values.len()` lanes, zero beyond
+    fn load_or_default(values: &[T]) -> Self;
+
+    /// Store the lanes where `mask` is set to the first `values.len()` ones
+    fn store_select(self, values: &mut [T], mask: [bool; LANES]);
+}
+
+macro_rules! impl_simd_lanes {
+    ($t:ty) => {
+        impl SimdLanes<$t> for Simd<$t, LANES> {
+            fn splat(value: $t) -> Self {
+                Simd::splat(value)
+            }
+
+            fn from_array(values: [$t; LANES]) -> Self {
+                Simd::from_array(values)
+            }
+
+            fn load_or_default(values: &[$t]) -> Self {
+                Simd::load_or_default(values)
+            }
+
+            fn store_select(self, values: &mut [$t], mask: [bool; LANES]) {
+                Simd::store_select(self, values, Mask::from_array(mask))
+            }
+        }
+    };
+}
+
+impl_simd_lanes!(f32);
+impl_simd_lanes!(f64);
+
+/// `c . v` for a lattice velocity `c`, whose components are -1, 0 or 1
+fn lattice_dot<T: NumTrait>(c: &[i32; 3], v: &[Lanes<T>; 3]) -> Lanes<T> {
+    let mut sum = Lanes::<T>::splat(T::zero());
+    for (c_d, v_d) in c.iter().zip(v) {
+        match c_d {
+            1 => sum += *v_d,
+            -1 => sum -= *v_d,
+            _ => {}
+        }
+    }
+    sum
+}
+
+fn dot<T: NumTrait>(a: &[Lanes<T>; 3], b: &[Lanes<T>; 3]) -> Lanes<T> {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Density and momentum of a group of nodes from their populations `f`
+pub fn moments_lanes<V: VelocitySet, T: NumTrait>(f: &[Lanes<T>]) -> (Lanes<T>, [Lanes<T>; 3]) {
+    let mut rho = Lanes::<T>::splat(T::zero());
+    let mut momentum = [Lanes::<T>::splat(T::zero()); 3];
+    for (f_i, c) in f.iter().zip(V::offsets()) {
+        rho += *f_i;
+        for (m_d, c_d) in momentum.iter_mut().zip(c) {
+            match c_d {
+                1 => *m_d += *f_i,
+                -1 => *m_d -= *f_i,
+                _ => {}
+            }
+        }
+    }
+    (rho, momentum)
+}
+
+/// BGK relaxation of the populations `f` of a group of nodes at density `rho` and
+/// half force shifted velocity `u`, with the Guo source of `force`. Same scheme as
+/// `Bgk` with `equilibrium` and `guo_source`, or `shifted_equilibrium` for
+/// `shifted` populations.
+pub fn bgk_lanes<V: VelocitySet, T: NumTrait>(
+    f: &mut [Lanes<T>],
+    rho: Lanes<T>,
+    u: &[Lanes<T>; 3],
+    force: Option<&[Lanes<T>; 3]>,
+    omega: T,
+    shifted: bool,
+) {
+    let splat = Lanes::<T>::splat;
+    let inv_c_sqr = splat(T::of(V::c_sqr()).recip());
+    let half = splat(T::of(0.5));
+    let omega_lanes = splat(omega);
+    let forcing = splat(T::one() - T::of(0.5) * omega);
+    let u_term = half * dot::<T>(u, u) * inv_c_sqr;
+    let base = splat(T::one()) - u_term;
+    let rest = rho - splat(T::one());
+    let u_force = force.map(|force| dot::<T>(u, force));
+    for (q_i, (f_i, c)) in f.iter_mut().zip(V::offsets()).enumerate() {
+        let w = splat(T::of(V::weights()[q_i]));
+        let t = lattice_dot::<T>(c, u) * inv_c_sqr;
+        let f_eq = match shifted {
+            true => w * (rest + rho * (t + half * t * t - u_term)),
+            false => w * rho * (base + t + half * t * t),
+        };
+        *f_i += omega_lanes * (f_eq - *f_i);
+        if let (Some(force), Some(u_force)) = (force, u_force) {
+            // ((c - u) / c_s^2 + c (c . u) / c_s^4) . F
+            let c_force = lattice_dot::<T>(c, force);
+            let term = (c_force - u_force) * inv_c_sqr + c_force * t * inv_c_sqr;
+            *f_i += forcing * w * term;
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::vector;
+
+    #[test]
+    fn lanes_match_bgk() {
+        let omega = 1.3_f32;
+        let u = vector![0.03, -0.02, 0.01];
+        let force = vector![1e-3, 2e-3, -1e-3];
+        let f: Vec<f32> = (0..D3Q27::Q).map(|q_i| D3Q27_W[q_i] as f32 * (1.0 + 0.01 * q_i as f32)).collect();
+        let rho: f32 = f.iter().sum();
+
+        let directions = D3Q27::gen_directions::<f32>();
+        let c_sqr = D3Q27::c_sqr() as f32;
+        let f_eq: Vec<f32> = (0..D3Q27::Q)
+            .map(|q_i| equilibrium(D3Q27_W[q_i] as f32, rho, directions[q_i].dot(&u), u.dot(&u), c_sqr))
+            .collect();
+        let source: Vec<f32> = (0..D3Q27::Q)
+            .map(|q_i| guo_source(D3Q27_W[q_i] as f32, &directions[q_i], &u, &force, c_sqr))
+            .collect();
+        let node = CollisionNode {
+            rho,
+            u,
+            force,
+            f: &f,
+            f_eq: &f_eq,
+            source: &source,
+            omega: None,
+        };
+        let mut expected = vec![0.0; D3Q27::Q];
+        CollisionOperator::<D3Q27>::collide(&Bgk { omega }, &node, &mut expected);
+
+        let mut lanes: Vec<Lanes<f32>> = f.iter().map(|f_i| Lanes::<f32>::splat(*f_i)).collect();
+        let (rho_lanes, _) = moments_lanes::<D3Q27, f32>(&lanes);
+        assert_eq!(rho_lanes, Lanes::<f32>::splat(rho));
+        let splat = |v: Vec3| [Lanes::<f32>::splat(v[0]), Lanes::<f32>::splat(v[1]), Lanes::<f32>::splat(v[2])];
+        bgk_lanes::<D3Q27, f32>(&mut lanes, rho_lanes, &splat(u), Some(&splat(force)), omega, false);
+        for (q_i, f_i) in lanes.iter().enumerate() {
+            assert!((f_i[LANES - 1] - expected[q_i]).abs() < 1e-6, "{}: {} {}", q_i, f_i[0], expected[q_i]);
+        }
+    }
+}
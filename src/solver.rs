@@ -1,12 +1,10 @@
 use crate::*;
 use lattice::*;
-use nalgebra::{matrix, vector};
+use nalgebra::vector;
+use std::marker::PhantomData;
 use vtkio::model::*;
-use rand::prelude::*;
 use rand::distributions::{Distribution, Uniform};
 
-
-
 // Coord Iterator
 pub fn coord_iter(aabb: AABB<3>) -> impl std::iter::Iterator<Item = Coord<3>> {
     let size = box_buffer_size(&aabb);
@@ -14,41 +12,124 @@ pub fn coord_iter(aabb: AABB<3>) -> impl std::iter::Iterator<Item = Coord<3>> {
 }
 
 pub fn cell_coord_iter(aabb: AABB<3>) -> impl std::iter::Iterator<Item = Coord<3>> {
-    let mut cell_bounds = aabb.clone();
+    let mut cell_bounds = aabb;
     cell_bounds.set_column(1, &cell_bounds.column(1).add_scalar(-1));
     let size = box_buffer_size(&cell_bounds);
     (0..size).map(move |index| linear_to_coord_in_box(index, &cell_bounds))
 }
 
 pub fn cell_count(aabb: AABB<3>) -> usize {
-    let mut cell_bounds = aabb.clone();
+    let mut cell_bounds = aabb;
     cell_bounds.set_column(1, &cell_bounds.column(1).add_scalar(-1));
     box_buffer_size(&cell_bounds)
 }
 
-pub struct Solver {
+/// Nodes visited by `Solver::fused_sweep`
+#[derive(Clone, Copy)]
+enum SweepNodes<'a, T> {
+    /// The non solid nodes of the grid
+    Grid,
+    /// The nodes of the allocated tiles
+    Tiles(&'a BlockSparse<T>),
+    /// The listed non solid nodes
+    Listed(&'a FluidNodes<T>),
+}
+
+/// How `Solver::step` moves the populations, see `Solver::set_propagation`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Propagation {
+    /// One sweep each for `streaming`, `apply_bcs`, `moments` and `collision`
+    #[default]
+    Split,
+
+    /// Pull streaming, boundary conditions, moments and collision in a single
+    /// sweep between the two population arrays
+    Fused,
+
+    /// The fused sweep on a single population array with the AA pattern. Even
+    /// steps keep the populations of a node in place, swapped to the opposite
+    /// directions, odd steps pull from and push to the neighbors.
+    InPlace,
+}
+
+impl Propagation {
+    /// Population layout for the kernels, struct of arrays for the fused ones
+    pub fn layout(&self) -> Layout {
+        match self {
+            Propagation::Split => Layout::Aos,
+            Propagation::Fused | Propagation::InPlace => Layout::Soa,
+        }
+    }
+}
+
+/// Buffers of the moments, taken out of the solver while a fused kernel writes them
+struct MomentBuffers<T> {
+    pressure: Vec<T>,
+    velocity: Vec<Vec3<T>>,
+    eddy_viscosity: Option<Vec<T>>,
+}
+
+pub struct Solver<V: VelocitySet = D3Q27, T: NumTrait = f32> {
     grid_dimensions: AABB<3>,
-    pub distributions: Array4D,
-    distributions_buffer: Array4D,
-    pressure: Array3D,
-    velocity: VelArray,
-    offsets: [Coord<3>; 27],
-    directions: [Vec3; 27],
-    omega: f32,
-    c_sqr: f32,
-    inflow_density: f32,
-    inflow_accel: f32,
+    pub distributions: Array4D<T>,
+    distributions_buffer: Array4D<T>,
+    pressure: Array3D<T>,
+    velocity: VelArray<T>,
+    offsets: Vec<Coord<3>>,
+    directions: Vec<Vec3<T>>,
+    /// `V::weights` in precision `T`
+    weights: Vec<T>,
+    collision: Box<dyn CollisionOperator<V, T>>,
+    c_sqr: T,
+    inflow_density: T,
+    periodic: [bool; 3],
+    boundaries: BoundaryRegistry<T>,
+    flags: FlagArray,
+    boundary_nodes: Vec<Vec<Coord<3>>>,
+    obstacles: Vec<Obstacle>,
+    node_types: NodeTypeArray,
+    wall_velocities: Vec<Option<Vec3<T>>>,
+    body_force: Option<BodyForce<T>>,
+    shan_chen: Option<ShanChen<T>>,
+    interaction_force: Option<VelArray<T>>,
+    smagorinsky: Option<Smagorinsky<T>>,
+    eddy_viscosity: Option<Array3D<T>>,
+    components: Vec<Component<T>>,
+    coupling: Option<ComponentCoupling<T>>,
+    scalar: Option<ScalarField<T>>,
+    boussinesq: Option<Boussinesq<T>>,
+    propagation: Propagation,
+
+    /// Parity of the next in place step
+    odd_step: bool,
+    simd: bool,
+    shifted: bool,
+    /// Current and next populations with `set_sparse`, replacing the dense ones
+    sparse: Option<(BlockSparse<T>, BlockSparse<T>)>,
+    /// Current and next populations with `set_indirect`, replacing the dense ones
+    indirect: Option<(FluidNodes<T>, FluidNodes<T>)>,
+    velocity_set: PhantomData<V>,
 }
 
-impl Solver {
+impl<V: VelocitySet, T: NumTrait> Solver<V, T> {
+    /// BGK solver relaxing at `omega`. A nonzero `inflow_accel` drives the flow along
+    /// x as a uniform body force of `inflow_density * inflow_accel`, see `set_body_force`.
     pub fn new(
         grid_dimensions: AABB<3>,
-        omega: f32,
-        c_sqr: f32,
-        inflow_density: f32,
-        inflow_accel: f32,
+        omega: T,
+        inflow_density: T,
+        inflow_accel: T,
     ) -> Self {
-        let q_bounds = nalgebra::matrix![0, 26];
+        Self::with_collision(grid_dimensions, Bgk { omega }, inflow_density, inflow_accel)
+    }
+
+    pub fn with_collision<C: CollisionOperator<V, T> + 'static>(
+        grid_dimensions: AABB<3>,
+        collision: C,
+        inflow_density: T,
+        inflow_accel: T,
+    ) -> Self {
+        let q_bounds = nalgebra::matrix![0, V::Q as i32 - 1];
         #[allow(clippy::toplevel_ref_arg)]
         let dimensions = nalgebra::stack![grid_dimensions; q_bounds];
 
@@ -58,248 +139,1419 @@ impl Solver {
             distributions_buffer: Array4D::new(dimensions),
             pressure: Array3D::new(grid_dimensions),
             velocity: VelArray::new(grid_dimensions),
-            offsets: gen_d3q27_offsets(),
-            directions: gen_d3q27_directions(),
-            omega,
-            c_sqr,
+            offsets: V::gen_offsets(),
+            directions: V::gen_directions(),
+            weights: V::weights().iter().map(|w_i| T::of(*w_i)).collect(),
+            collision: Box::new(collision),
+            c_sqr: T::of(V::c_sqr()),
             inflow_density,
-            inflow_accel,
+            periodic: [false; 3],
+            boundaries: BoundaryRegistry::closed_box(),
+            flags: FlagArray::new(grid_dimensions),
+            boundary_nodes: Vec::new(),
+            obstacles: Vec::new(),
+            node_types: NodeTypeArray::new(grid_dimensions),
+            wall_velocities: Vec::new(),
+            body_force: (inflow_accel != T::zero())
+                .then(|| BodyForce::Uniform(vector![inflow_density * inflow_accel, T::zero(), T::zero()])),
+            shan_chen: None,
+            interaction_force: None,
+            smagorinsky: None,
+            eddy_viscosity: None,
+            components: Vec::new(),
+            coupling: None,
+            scalar: None,
+            boussinesq: None,
+            propagation: Propagation::Split,
+            odd_step: false,
+            simd: false,
+            shifted: false,
+            sparse: None,
+            indirect: None,
+            velocity_set: PhantomData,
         };
+        result.resolve_boundaries();
         result
     }
 
+    /// Replace the boundary assignments, the default is `BoundaryRegistry::closed_box`
+    pub fn set_boundaries(&mut self, boundaries: BoundaryRegistry<T>) {
+        self.boundaries = boundaries;
+        self.resolve_boundaries();
+    }
+
+    pub fn boundaries(&self) -> &BoundaryRegistry<T> {
+        &self.boundaries
+    }
+
+    pub fn flags(&self) -> &FlagArray {
+        &self.flags
+    }
+
+    pub fn node_types(&self) -> &NodeTypeArray {
+        &self.node_types
+    }
+
+    /// Mark the nodes of `obstacle` solid, on top of any walls from the boundaries
+    pub fn add_obstacle(&mut self, obstacle: Obstacle) {
+        self.obstacles.push(obstacle);
+        self.resolve_boundaries();
+    }
+
+    /// Voxelize `mesh` into solid nodes, the mesh must already be in lattice
+    /// coordinates, see `TriangleMesh::transform` and `TriangleMesh::fit_to_box`
+    pub fn add_geometry(&mut self, mesh: &TriangleMesh) -> VoxelizationReport {
+        let nodes = mesh.voxelize(&self.grid_dimensions);
+        let solid_nodes = nodes.len();
+        self.add_obstacle(Obstacle::Nodes(nodes));
+        VoxelizationReport {
+            solid_nodes,
+            fluid_nodes: self.node_types.size() - self.node_types.count(NodeType::Solid),
+            wetted_nodes: self.wetted_node_count(),
+        }
+    }
+
+    /// Non solid nodes with a solid neighbor along any lattice link, walls included
+    pub fn wetted_node_count(&self) -> usize {
+        coord_iter(self.grid_dimensions)
+            .filter(|coord| {
+                self.node_types.get(coord) != NodeType::Solid
+                    && self.offsets.iter().any(|offset| {
+                        let neighbor = periodic_wrap(&self.grid_dimensions, &(coord + offset), &self.periodic);
+                        box_contains_coord(&self.grid_dimensions, &neighbor)
+                            && self.node_types.get(&neighbor) == NodeType::Solid
+                    })
+            })
+            .count()
+    }
+
+    /// Resolve the registry into the flag field and node types, and gather the
+    /// nodes of each assignment. Walls and obstacles become solid nodes.
+    fn resolve_boundaries(&mut self) {
+        assert!(self.dense(), "change the geometry and boundaries before set_sparse or set_indirect");
+        self.flags = self.boundaries.resolve(&self.grid_dimensions);
+        self.periodic = self.boundaries.periodic_axes();
+        self.node_types = NodeTypeArray::new(self.grid_dimensions);
+        for coord in coord_iter(self.grid_dimensions) {
+            let flag = self.flags.get(&coord);
+            if flag != FLUID_FLAG {
+                let node_type = match self.boundaries.assignments()[flag as usize - 1].1 {
+                    BoundaryType::Wall | BoundaryType::MovingWall { .. } => NodeType::Solid,
+                    _ => NodeType::Boundary,
+                };
+                self.node_types.set(&coord, node_type);
+            }
+        }
+        for obstacle in &self.obstacles {
+            for coord in obstacle.solid_nodes(&self.grid_dimensions) {
+                self.node_types.set(&coord, NodeType::Solid);
+            }
+        }
+
+        // Indexed by flag, obstacles on fluid flagged nodes are stationary
+        self.wall_velocities = std::iter::once(None)
+            .chain(self.boundaries.assignments().iter().map(|(_, boundary)| match boundary {
+                BoundaryType::MovingWall { velocity } => Some(*velocity),
+                _ => None,
+            }))
+            .collect();
+
+        self.boundary_nodes = vec![Vec::new(); self.boundaries.assignments().len()];
+        for coord in coord_iter(self.grid_dimensions) {
+            let flag = self.flags.get(&coord);
+            if self.node_types.get(&coord) == NodeType::Boundary {
+                self.boundary_nodes[flag as usize - 1].push(coord);
+            }
+        }
+    }
+
+    /// Wrap populations leaving the domain along `axis` around to the opposite face
+    /// instead of dropping them. Turning periodicity off puts walls on both faces.
+    pub fn set_periodic(&mut self, axis: usize, periodic: bool) {
+        if periodic {
+            self.boundaries.set_periodic(axis);
+        } else {
+            for face in Face::all() {
+                if face.axis() == axis {
+                    self.boundaries.set_face(face, BoundaryType::Wall);
+                }
+            }
+        }
+        self.resolve_boundaries();
+    }
+
+    pub fn periodic(&self) -> [bool; 3] {
+        self.periodic
+    }
+
+    /// Make `face` a wall moving tangentially at `velocity`, e.g. the lid of a cavity
+    pub fn set_moving_wall(&mut self, face: Face, velocity: Vec3<T>) {
+        self.boundaries.set_face(face, BoundaryType::MovingWall { velocity });
+        self.resolve_boundaries();
+    }
+
+    /// Drive the flow with `force`, applied in `collision` with the Guo scheme
+    pub fn set_body_force(&mut self, force: BodyForce<T>) {
+        self.body_force = Some(force);
+    }
+
+    pub fn clear_body_force(&mut self) {
+        self.body_force = None;
+    }
+
+    pub fn body_force(&self) -> Option<&BodyForce<T>> {
+        self.body_force.as_ref()
+    }
+
+    /// Multiphase flow, the Shan-Chen interaction force is computed from the
+    /// density in `moments` and applied on top of the body force
+    pub fn set_shan_chen(&mut self, model: ShanChen<T>) {
+        self.shan_chen = Some(model);
+        self.interaction_force = Some(VelArray::new(self.grid_dimensions));
+    }
+
+    /// Shan-Chen force of the last `moments`, with a multiphase model
+    pub fn interaction_force(&self) -> Option<&VelArray<T>> {
+        self.interaction_force.as_ref()
+    }
+
+    /// Add a fluid component relaxing at `omega`, returns its number. The solver's
+    /// own populations are component 0, they keep the collision operator.
+    pub fn add_component(&mut self, omega: T) -> usize {
+        let dimensions = *self.distributions.dimensions();
+        self.components.push(Component::new(dimensions, omega));
+        self.components.len()
+    }
+
+    /// Component `number` from `add_component`
+    pub fn component(&self, number: usize) -> &Component<T> {
+        &self.components[number - 1]
+    }
+
+    pub fn component_mut(&mut self, number: usize) -> &mut Component<T> {
+        &mut self.components[number - 1]
+    }
+
+    pub fn component_count(&self) -> usize {
+        self.components.len() + 1
+    }
+
+    /// Shan-Chen forces between the components, computed in `moments` on top of
+    /// the single component interaction force
+    pub fn set_component_coupling(&mut self, coupling: ComponentCoupling<T>) {
+        assert_eq!(coupling.component_count(), self.component_count());
+        self.coupling = Some(coupling);
+        if self.interaction_force.is_none() {
+            self.interaction_force = Some(VelArray::new(self.grid_dimensions));
+        }
+    }
+
+    /// Passive scalar named `name`, diffusing at rate `omega` and advected with the
+    /// flow in the same steps. Configure it through `scalar_mut`.
+    pub fn set_scalar(&mut self, name: &str, omega: T) {
+        self.scalar = Some(ScalarField::new(name, self.grid_dimensions, omega));
+    }
+
+    pub fn scalar(&self) -> Option<&ScalarField<T>> {
+        self.scalar.as_ref()
+    }
+
+    pub fn scalar_mut(&mut self) -> Option<&mut ScalarField<T>> {
+        self.scalar.as_mut()
+    }
+
+    /// Natural convection, the scalar from `set_scalar` is the temperature and
+    /// its buoyancy is added to the body force
+    pub fn set_boussinesq(&mut self, model: Boussinesq<T>) {
+        assert!(self.scalar.is_some(), "Boussinesq coupling needs a temperature field, see set_scalar");
+        self.boussinesq = Some(model);
+    }
+
+    /// Nusselt number at a face with fixed temperature, see `nusselt`
+    pub fn nusselt(&self, face: Face) -> Option<T> {
+        self.scalar.as_ref().and_then(|scalar| nusselt(scalar, &self.node_types, face))
+    }
+
+    /// Density of all components at `coord`
+    fn total_density(&self, coord: &Coord<3>) -> T {
+        self.pressure.get(coord) + self.components.iter().map(|c| c.density.get(coord)).sum::<T>()
+    }
+
+    /// Share of the body force carried by a component of density `rho`, in
+    /// proportion to its density
+    fn body_force_on(&self, coord: &Coord<3>, rho: T) -> Option<Vec3<T>> {
+        if self.components.is_empty() {
+            return self.body_force_at(coord, rho);
+        }
+        let total = self.total_density(coord);
+        let body = self.body_force_at(coord, total);
+        body.map(|force| if total.abs() > T::of(0.00001) { force * (rho / total) } else { Vec3::zero() })
+    }
+
+    /// Body force plus buoyancy at `coord`, on all components together of density `total`
+    fn body_force_at(&self, coord: &Coord<3>, total: T) -> Option<Vec3<T>> {
+        let body = self.body_force.as_ref().map(|force| force.at(coord));
+        let buoyancy = match (&self.boussinesq, &self.scalar) {
+            (Some(model), Some(scalar)) => Some(model.force(total, scalar.value().get(coord))),
+            _ => None,
+        };
+        match (body, buoyancy) {
+            (None, None) => None,
+            (body, buoyancy) => Some(body.unwrap_or_else(Vec3::zero) + buoyancy.unwrap_or_else(Vec3::zero)),
+        }
+    }
+
+    /// Body force plus interaction force on component 0 of density `rho` at
+    /// `coord`, `None` when there is neither
+    fn total_force(&self, coord: &Coord<3>, rho: T) -> Option<Vec3<T>> {
+        let body = self.body_force_on(coord, rho);
+        let interaction = self.interaction_force.as_ref().map(|force| force.get(coord));
+        match (body, interaction) {
+            (None, None) => None,
+            (body, interaction) => Some(body.unwrap_or_else(Vec3::zero) + interaction.unwrap_or_else(Vec3::zero)),
+        }
+    }
+
+    /// Large eddy simulation, `collision` relaxes each node at the rate of the
+    /// molecular plus the eddy viscosity
+    pub fn set_smagorinsky(&mut self, model: Smagorinsky<T>) {
+        self.smagorinsky = Some(model);
+        self.eddy_viscosity = Some(Array3D::new(self.grid_dimensions));
+    }
+
+    /// Eddy viscosity of the last `collision`, with a turbulence model
+    pub fn eddy_viscosity(&self) -> Option<&Array3D<T>> {
+        self.eddy_viscosity.as_ref()
+    }
+
+    pub fn density(&self) -> &Array3D<T> {
+        &self.pressure
+    }
+
+    pub fn velocity(&self) -> &VelArray<T> {
+        &self.velocity
+    }
+
     pub fn equilibrium_init(&mut self) {
+        assert!(self.dense(), "initialize before set_sparse or set_indirect");
         for coord in coord_iter(self.grid_dimensions) {
-            for q in 0..27 {
-                let value = self.inflow_density * D3Q27_W[q];
-                self.distributions.set_q(&coord, q as i32, value)
+            for (q_i, w_i) in self.weights.iter().enumerate() {
+                let value = match self.shifted {
+                    true => (self.inflow_density - T::one()) * *w_i,
+                    false => self.inflow_density * *w_i,
+                };
+                self.distributions.set_q(&coord, q_i as i32, value)
             }
         }
     }
 
     pub fn flow_init(&mut self) {
+        assert!(self.dense(), "initialize before set_sparse or set_indirect");
+        let up = V::index_of([0, 0, 1]).expect("flow_init requires a three dimensional velocity set");
+        let down = V::index_of([0, 0, -1]).expect("flow_init requires a three dimensional velocity set");
 
         let mut rng = rand::thread_rng();
         let dist = Uniform::from(0.0..0.1);
         for coord in coord_iter(self.grid_dimensions) {
-            for q_i in 0..27 {
-                let value = self.inflow_density * dist.sample(&mut rng);
-                self.distributions.set_q(&coord, q_i, value);
+            for q_i in 0..V::Q {
+                let value = self.inflow_density * T::of(dist.sample(&mut rng));
+                self.distributions.set_q(&coord, q_i as i32, value);
             }
 
-            if coord[1] > 20 {
-                let q_i = 6;
-                let w_i = D3Q27_W[q_i];
-                let w = 1.0 / w_i;
-                let value = self.inflow_density * w;
-                self.distributions.set_q(&coord, q_i as i32, value);
-            } else {
-                let q_i = 5;
-                let w_i = D3Q27_W[q_i];
-                let w = 1.0 / w_i;
-                let value = self.inflow_density * w;
-                self.distributions.set_q(&coord, q_i as i32, value);
+            let q_i = if coord[1] > 20 { down } else { up };
+            let w_i = self.weights[q_i];
+            let w = w_i.recip();
+            let value = self.inflow_density * w;
+            self.distributions.set_q(&coord, q_i as i32, value);
+        }
+        if self.shifted {
+            self.shifted = false;
+            self.set_shifted(true);
+        }
+    }
+
+    /// Store the primary populations as `f - w_i`, their deviation from rest at
+    /// unit density, which keeps more significant bits in single precision. The
+    /// current populations are converted. `population` still gives `f`.
+    pub fn set_shifted(&mut self, shifted: bool) {
+        if shifted == self.shifted {
+            return;
+        }
+        let sign = if shifted { -T::one() } else { T::one() };
+        let listed = self.sparse.as_mut().map(|(sparse, _)| &mut sparse.buffer);
+        let listed = listed.or(self.indirect.as_mut().map(|(indirect, _)| &mut indirect.buffer));
+        for (index, q) in listed.into_iter().flatten().enumerate() {
+            *q += sign * self.weights[index % V::Q];
+        }
+        for node in 0..self.distributions.size() / V::Q {
+            for (q_i, w_i) in self.weights.iter().enumerate() {
+                let index = self.distributions.buffer_index(node, q_i);
+                self.distributions.buffer[index] += sign * *w_i;
             }
         }
+        self.shifted = shifted;
     }
 
+    pub fn shifted(&self) -> bool {
+        self.shifted
+    }
+
+    /// Equilibrium in the form the populations are stored in, see `set_shifted`
+    fn stored_equilibrium(&self, w_i: T, rho: T, c_u: T, u_sqr: T) -> T {
+        match self.shifted {
+            true => shifted_equilibrium(w_i, rho, c_u, u_sqr, self.c_sqr),
+            false => equilibrium(w_i, rho, c_u, u_sqr, self.c_sqr),
+        }
+    }
+
+    /// Whether stored populations must be unshifted around the collision operator
+    fn unshift_for_collision(&self) -> bool {
+        self.shifted && !self.collision.shift_invariant()
+    }
+
+    /// Pull streaming. Populations that would be pulled from a solid node, or from
+    /// outside a non-periodic face, are bounced back halfway: the node's own
+    /// post-collision population in the opposite direction is used instead.
+    /// Moving walls add `2 w_i rho (c_i . u_w) / c_s^2` to the reflected population.
     pub fn streaming(&mut self) {
-        for coord in coord_iter(self.grid_dimensions) {
-            for q_i in 0..27 {
-                // Get q value
-                let q = self.distributions.get_q(&coord, q_i);
+        assert!(self.propagation != Propagation::InPlace, "in place populations only advance through step");
+        assert!(self.dense(), "sparse or indirect populations only advance through step");
+        let mut buffer = std::mem::take(&mut self.distributions_buffer);
+        self.stream(&self.distributions, &self.pressure, &mut buffer);
+        self.distributions_buffer = std::mem::replace(&mut self.distributions, buffer);
 
-                // Get neighbor intex
-                let neighbor_coord = coord + self.offsets[q_i as usize];
+        let mut components = std::mem::take(&mut self.components);
+        for component in &mut components {
+            self.stream(&component.distributions, &component.density, &mut component.distributions_buffer);
+            std::mem::swap(&mut component.distributions, &mut component.distributions_buffer);
+        }
+        self.components = components;
 
-                if box_contains_coord(&self.grid_dimensions, &neighbor_coord) {
-                    self.distributions_buffer.set_q(&neighbor_coord, q_i, q);
-                }
+        if let Some(scalar) = &mut self.scalar {
+            scalar.stream(&self.node_types, &self.periodic);
+        }
+    }
+
+    /// Stream `source` into `target`, `density` is the one of the reflected
+    /// populations at moving walls
+    fn stream(&self, source: &Array4D<T>, density: &Array3D<T>, target: &mut Array4D<T>) {
+        for_each_node_q(target, || (), |_, coord, out| {
+            if self.node_types.get(&coord) == NodeType::Solid {
+                return;
             }
+            let rho = density.get(&coord);
+            for (q_i, q) in out.iter_mut().enumerate() {
+                *q = self.pull(source, &coord, q_i, rho);
+            }
+        });
+    }
+
+    /// Population `q_i` streamed into `coord` from `source`, `rho` is the density
+    /// of the node for moving walls
+    fn pull(&self, source: &Array4D<T>, coord: &Coord<3>, q_i: usize, rho: T) -> T {
+        let (neighbor, in_domain, fluid) = self.link(coord, -self.offsets[q_i]);
+        if fluid {
+            source.get_q(&neighbor, q_i as i32)
+        } else {
+            let reflected = source.get_q(coord, V::opposites()[q_i] as i32);
+            self.bounce_back(reflected, &neighbor, in_domain, q_i, rho)
         }
-        std::mem::swap(&mut self.distributions, &mut self.distributions_buffer);
     }
 
+    /// `pull` from block sparse populations, crossing tiles through the neighbor table
+    fn pull_sparse(&self, source: &BlockSparse<T>, tile: usize, coord: &Coord<3>, q_i: usize, rho: T) -> T {
+        let offset = -self.offsets[q_i];
+        let (neighbor, in_domain, fluid) = self.link(coord, offset);
+        if fluid {
+            source.buffer[source.neighbor_index(tile, coord, &offset, &neighbor, q_i)]
+        } else {
+            let reflected = source.buffer[source.index(tile, coord, V::opposites()[q_i])];
+            self.bounce_back(reflected, &neighbor, in_domain, q_i, rho)
+        }
+    }
+
+    /// The node `coord + offset` after periodic wrapping, whether it is in the
+    /// domain and whether it is a non solid node there
+    fn link(&self, coord: &Coord<3>, offset: Coord<3>) -> (Coord<3>, bool, bool) {
+        let neighbor = periodic_wrap(&self.grid_dimensions, &(coord + offset), &self.periodic);
+        let in_domain = box_contains_coord(&self.grid_dimensions, &neighbor);
+        let fluid = in_domain && self.node_types.get(&neighbor) != NodeType::Solid;
+        (neighbor, in_domain, fluid)
+    }
+
+    /// Population `q_i` of a link cut by a wall at `neighbor`, from the `reflected`
+    /// one. A moving wall adds momentum in proportion to the density `rho`.
+    fn bounce_back(&self, reflected: T, neighbor: &Coord<3>, in_domain: bool, q_i: usize, rho: T) -> T {
+        let wall_velocity = if in_domain {
+            self.wall_velocities[self.flags.get(neighbor) as usize]
+        } else {
+            None
+        };
+        match wall_velocity {
+            Some(u_w) => {
+                let c_u = self.directions[q_i].dot(&u_w);
+                reflected + T::of(2.0) * self.weights[q_i] * rho * c_u / self.c_sqr
+            }
+            None => reflected,
+        }
+    }
+
+    /// Density and velocity, with a body force the velocity is shifted by half
+    /// the force as the Guo scheme requires. With several components the velocity
+    /// is the one of the mixture, shifted by half the force on all of them.
     pub fn moments(&mut self) {
-        for coord in coord_iter(self.grid_dimensions) {
-            let mut pressure = 0.0;
-            let mut u = Vec3::zero();
-            for q_i in 0..27 {
-                let q = self.distributions.get_q(&coord, q_i);
-                pressure += q;
-                u += self.directions[q_i as usize] * q;
+        assert!(self.dense(), "sparse or indirect populations only advance through step");
+        let grid_dimensions = self.grid_dimensions;
+        if self.simd_moments() {
+            self.moment_sums_simd();
+        } else {
+            self.moment_sums();
+        }
+        for component in &mut self.components {
+            for_each_node(&grid_dimensions, &mut component.density.buffer, 1, || (), |_, coord, density| {
+                if self.node_types.get(&coord) == NodeType::Solid {
+                    return;
+                }
+                density[0] = (0..V::Q).map(|q_i| component.distributions.get_q(&coord, q_i as i32)).sum();
+            });
+        }
+
+        // The interaction forces need the density of the neighbors
+        if let (Some(model), Some(force)) = (&self.shan_chen, &mut self.interaction_force) {
+            model.interaction_force::<V>(&self.pressure, &self.node_types, &self.grid_dimensions, &self.periodic, force);
+        }
+        if let (Some(coupling), Some(force)) = (&self.coupling, &mut self.interaction_force) {
+            if self.shan_chen.is_none() {
+                *force = VelArray::new(self.grid_dimensions);
             }
-            if pressure.abs() > 0.00001 {
-                u /= pressure;
-            } 
-            self.pressure.set(&coord, pressure);
-            self.velocity.set(&coord, u);
+            let mut densities = vec![&self.pressure];
+            let mut forces = vec![force];
+            for component in &mut self.components {
+                component.force = VelArray::new(self.grid_dimensions);
+                densities.push(&component.density);
+                forces.push(&mut component.force);
+            }
+            coupling.add_forces::<V>(&densities, &self.node_types, &self.grid_dimensions, &self.periodic, &mut forces);
+        }
+        if let Some(scalar) = &mut self.scalar {
+            scalar.moments(&self.node_types);
         }
+
+        let mut velocity = std::mem::take(&mut self.velocity);
+        for_each_node(&grid_dimensions, &mut velocity.buffer, 1, || (), |_, coord, u| {
+            if self.node_types.get(&coord) == NodeType::Solid {
+                return;
+            }
+            let pressure = self.total_density(&coord);
+            if let Some(force) = self.total_force(&coord, self.pressure.get(&coord)) {
+                u[0] += force * T::of(0.5);
+            }
+            for component in &self.components {
+                let force = self.body_force_on(&coord, component.density.get(&coord)).unwrap_or_else(Vec3::zero);
+                u[0] += (force + component.force.get(&coord)) * T::of(0.5);
+            }
+            if pressure.abs() > T::of(0.00001) {
+                u[0] /= pressure;
+            }
+        });
+        self.velocity = velocity;
+    }
+
+    /// Density and momentum of every node, into `pressure` and `velocity`
+    fn moment_sums(&mut self) {
+        for_each_node_zip(
+            &self.grid_dimensions,
+            &mut self.pressure.buffer,
+            1,
+            &mut self.velocity.buffer,
+            || (),
+            |_, coord, pressure, momentum| {
+                if self.node_types.get(&coord) == NodeType::Solid {
+                    return;
+                }
+                pressure[0] = T::zero();
+                *momentum = Vec3::zero();
+                for q_i in 0..V::Q {
+                    let q = self.distributions.get_q(&coord, q_i as i32);
+                    pressure[0] += q;
+                    *momentum += self.directions[q_i] * q;
+                }
+                if self.shifted {
+                    pressure[0] += T::one();
+                }
+                for component in &self.components {
+                    for q_i in 0..V::Q {
+                        *momentum += self.directions[q_i] * component.distributions.get_q(&coord, q_i as i32);
+                    }
+                }
+            },
+        );
+    }
+
+    /// Swap the collision operator, e.g. to compare operators on the same case
+    pub fn set_collision<C: CollisionOperator<V, T> + 'static>(&mut self, collision: C) {
+        self.collision = Box::new(collision);
+    }
+
+    pub fn collision_operator(&self) -> &dyn CollisionOperator<V, T> {
+        self.collision.as_ref()
     }
 
     pub fn collision(&mut self) {
-        for coord in coord_iter(self.grid_dimensions) {
+        assert!(self.dense(), "sparse or indirect populations only advance through step");
+        let grid_dimensions = self.grid_dimensions;
+        // Scratch for the equilibrium, the forcing term and the relaxed populations
+        let scratch = || (vec![T::zero(); V::Q], vec![T::zero(); V::Q], vec![T::zero(); V::Q]);
+
+        // The eddy viscosity first, the relaxation rate of each node follows from it
+        let tau_0 = self.collision.omega().recip();
+        if let (Some(model), Some(mut eddy_viscosity)) = (self.smagorinsky, self.eddy_viscosity.take()) {
+            for_each_node(&grid_dimensions, &mut eddy_viscosity.buffer, 1, scratch, |(f, f_eq, _), coord, nu| {
+                if self.node_types.get(&coord) == NodeType::Solid {
+                    return;
+                }
+                let u = self.velocity.get(&coord);
+                let p = self.pressure.get(&coord);
+                for q_i in 0..V::Q {
+                    let dir_u = self.directions[q_i].dot(&u);
+                    f_eq[q_i] = self.stored_equilibrium(self.weights[q_i], p, dir_u, u.dot(&u));
+                    f[q_i] = self.distributions.get_q(&coord, q_i as i32);
+                }
+                let stress = non_equilibrium_stress(&self.directions, f, f_eq);
+                let tau = model.relaxation_time(tau_0, &stress, p, self.c_sqr);
+                nu[0] = self.c_sqr * (tau - tau_0);
+            });
+            self.eddy_viscosity = Some(eddy_viscosity);
+        }
+
+        let simd_omega = self.simd_omega();
+        let mut distributions = std::mem::take(&mut self.distributions);
+        if let Some(omega) = simd_omega {
+            self.collision_simd(&mut distributions, omega);
+        } else {
+            self.collision_scalar(&mut distributions);
+        }
+        self.distributions = distributions;
+
+        let mut components = std::mem::take(&mut self.components);
+        for component in &mut components {
+            let bgk = Bgk { omega: component.omega() };
+            for_each_node_q(
+                &mut component.distributions,
+                scratch,
+                |(f_eq, source, relaxed), coord, f| {
+                    if self.node_types.get(&coord) == NodeType::Solid {
+                        return;
+                    }
+                    let u = self.velocity.get(&coord);
+                    let rho = component.density.get(&coord);
+                    let u_sqr = u.dot(&u);
+                    let force =
+                        self.body_force_on(&coord, rho).unwrap_or_else(Vec3::zero) + component.force.get(&coord);
+                    for q_i in 0..V::Q {
+                        let dir_u = self.directions[q_i].dot(&u);
+                        let w_i = self.weights[q_i];
+                        f_eq[q_i] = equilibrium(w_i, rho, dir_u, u_sqr, self.c_sqr);
+                        source[q_i] = guo_source(w_i, &self.directions[q_i], &u, &force, self.c_sqr);
+                    }
+                    let node = CollisionNode {
+                        rho,
+                        u,
+                        force,
+                        f,
+                        f_eq,
+                        source,
+                        omega: None,
+                    };
+                    CollisionOperator::<V, T>::collide(&bgk, &node, relaxed);
+                    f.copy_from_slice(relaxed);
+                },
+            );
+        }
+        self.components = components;
+
+        if let Some(scalar) = &mut self.scalar {
+            scalar.collide(&self.velocity, &self.node_types);
+        }
+    }
+
+    /// Collision of `distributions` with the collision operator, node by node
+    fn collision_scalar(&self, distributions: &mut Array4D<T>) {
+        let tau_0 = self.collision.omega().recip();
+        let unshift = self.unshift_for_collision();
+        let scratch = || (vec![T::zero(); V::Q], vec![T::zero(); V::Q], vec![T::zero(); V::Q]);
+        for_each_node_q(distributions, scratch, |(f_eq, source, relaxed), coord, f| {
+            if self.node_types.get(&coord) == NodeType::Solid {
+                return;
+            }
             let u = self.velocity.get(&coord);
             let p = self.pressure.get(&coord);
-            let mut i_d = 0.0;
-            let mut n_d = 0.0;
-            let mut eq_d = 0.0;
-            for q_i in 0..27 {
+            let u_sqr = u.dot(&u);
+            let force = self.total_force(&coord, p);
+            for q_i in 0..V::Q {
                 // Calculate equilibrium
-                let dir = self.directions[q_i as usize];
-                let dir_u = dir.dot(&u);
-                let w_i = D3Q27_W[q_i as usize];
+                let dir_u = self.directions[q_i].dot(&u);
+                let w_i = self.weights[q_i];
+                f_eq[q_i] = self.stored_equilibrium(w_i, p, dir_u, u_sqr);
+                source[q_i] = match &force {
+                    Some(force) => guo_source(w_i, &self.directions[q_i], &u, force, self.c_sqr),
+                    None => T::zero(),
+                };
+            }
+            let omega = self.eddy_viscosity.as_ref().map(|nu| (tau_0 + nu.get(&coord) / self.c_sqr).recip());
+            if unshift {
+                shift_by_weights::<V, T>(f, T::one());
+                shift_by_weights::<V, T>(f_eq, T::one());
+            }
+
+            // relax
+            let node = CollisionNode {
+                rho: p,
+                u,
+                force: force.unwrap_or_else(Vec3::zero),
+                f,
+                f_eq,
+                source,
+                omega,
+            };
+            self.collision.collide(&node, relaxed);
+            if unshift {
+                shift_by_weights::<V, T>(relaxed, -T::one());
+            }
+            f.copy_from_slice(relaxed);
+        });
+    }
+
+    /// Run `moments` and BGK `collision` with the kernels of `simd` on groups of
+    /// `LANES` nodes, switching to the struct of arrays layout they need. Cases they
+    /// do not cover, other collision operators, turbulence models and several
+    /// components, keep the scalar kernels. Results agree with those to rounding.
+    pub fn set_simd(&mut self, simd: bool) {
+        self.simd = simd;
+        if simd {
+            self.set_layout(Layout::Soa);
+        }
+    }
+
+    fn simd_moments(&self) -> bool {
+        self.simd && self.layout() == Layout::Soa && self.components.is_empty()
+    }
+
+    /// BGK rate for the SIMD collision, `None` when it does not cover the case
+    fn simd_omega(&self) -> Option<T> {
+        if !self.simd_moments() || self.smagorinsky.is_some() || self.interaction_force.is_some() {
+            return None;
+        }
+        self.collision.bgk_omega()
+    }
 
-                let t1 = (3.0 * dir_u) / self.c_sqr;
-                let t2 = (9.0 * dir_u * dir_u) / (2.0 * self.c_sqr * self.c_sqr);
-                let t3 = -(3.0 * u.dot(&u)) / (2.0 * self.c_sqr);
-                let q_eq = w_i * p * (1.0 + t1 + t2 + t3);
+    /// `moment_sums` with the SIMD kernels
+    fn moment_sums_simd(&mut self) {
+        let node_count = self.pressure.size();
+        let mut pressure = std::mem::take(&mut self.pressure.buffer);
+        let mut velocity = std::mem::take(&mut self.velocity.buffer);
+        {
+            let (pressure, velocity) = (Disjoint::new(&mut pressure), Disjoint::new(&mut velocity));
+            let scratch = || vec![Lanes::<T>::splat(T::zero()); V::Q];
+            for_each_node_group(&self.grid_dimensions, LANES, scratch, |f, nodes| {
+                for (q_i, f_i) in f.iter_mut().enumerate() {
+                    *f_i = Lanes::<T>::load_or_default(&self.distributions.buffer[q_i * node_count..][nodes.clone()]);
+                }
+                let (mut rho, momentum) = moments_lanes::<V, T>(f);
+                if self.shifted {
+                    rho += Lanes::<T>::splat(T::one());
+                }
+                for (lane, node) in nodes.enumerate() {
+                    if self.node_types.get_index(node) == NodeType::Solid {
+                        continue;
+                    }
+                    // SAFETY: a node only touches its own moments
+                    unsafe {
+                        pressure.set(node, rho[lane]);
+                        velocity.set(node, vector![momentum[0][lane], momentum[1][lane], momentum[2][lane]]);
+                    }
+                }
+            });
+        }
+        self.pressure.buffer = pressure;
+        self.velocity.buffer = velocity;
+    }
 
-                // relax
-                let q = self.distributions.get_q(&coord, q_i);
-                let new_q = q + self.omega * (q_eq - q);
-                self.distributions.set_q(&coord, q_i, new_q);
+    /// BGK collision of `distributions` at `omega` with the SIMD kernels
+    fn collision_simd(&self, distributions: &mut Array4D<T>, omega: T) {
+        let node_count = self.pressure.size();
+        let forced = self.body_force.is_some() || self.boussinesq.is_some();
+        let mut buffer = std::mem::take(&mut distributions.buffer);
+        {
+            let populations = Disjoint::new(&mut buffer);
+            let scratch = || vec![Lanes::<T>::splat(T::zero()); V::Q];
+            for_each_node_group(&self.grid_dimensions, LANES, scratch, |f, nodes| {
+                let mut fluid = [false; LANES];
+                let mut u = [[T::zero(); LANES]; 3];
+                let mut force = [[T::zero(); LANES]; 3];
+                for (lane, node) in nodes.clone().enumerate() {
+                    fluid[lane] = self.node_types.get_index(node) != NodeType::Solid;
+                    let u_node = self.velocity.buffer[node];
+                    let force_node = match forced && fluid[lane] {
+                        true => {
+                            let coord = linear_to_coord_in_box(node, &self.grid_dimensions);
+                            self.total_force(&coord, self.pressure.buffer[node]).unwrap_or_else(Vec3::zero)
+                        }
+                        false => Vec3::zero(),
+                    };
+                    for d in 0..3 {
+                        u[d][lane] = u_node[d];
+                        force[d][lane] = force_node[d];
+                    }
+                }
+                let rho = Lanes::<T>::load_or_default(&self.pressure.buffer[nodes.clone()]);
+                let u = u.map(Lanes::<T>::from_array);
+                let force = force.map(Lanes::<T>::from_array);
 
-                i_d += q;
-                n_d += new_q;
-                eq_d += q_eq;
+                // SAFETY: a node only touches its own populations
+                let field = |q_i: usize| {
+                    let start = q_i * node_count + nodes.start;
+                    unsafe { populations.slice(start..start + nodes.len()) }
+                };
+                for (q_i, f_i) in f.iter_mut().enumerate() {
+                    *f_i = Lanes::<T>::load_or_default(field(q_i));
+                }
+                bgk_lanes::<V, T>(f, rho, &u, forced.then_some(&force), omega, self.shifted);
+                for (q_i, f_i) in f.iter().enumerate() {
+                    f_i.store_select(field(q_i), fluid);
+                }
+            });
+        }
+        distributions.buffer = buffer;
+    }
+
+    /// Switch the kernels of `step`, along with the layout they prefer. Leaving or
+    /// entering `InPlace` converts the populations, in place there is no
+    /// `distributions_buffer`.
+    pub fn set_propagation(&mut self, propagation: Propagation) {
+        if propagation == self.propagation {
+            return;
+        }
+        assert!(self.dense(), "sparse or indirect populations always use fused propagation");
+        let dimensions = *self.distributions.dimensions();
+        if self.propagation == Propagation::InPlace {
+            let mut distributions = Array4D::with_layout(dimensions, self.layout());
+            for coord in coord_iter(self.grid_dimensions) {
+                for q_i in 0..V::Q {
+                    distributions.set_q(&coord, q_i as i32, self.stored_population(&coord, q_i));
+                }
             }
-            println!("q: {}, eq_q: {}, n_d: {}, p: {}, u: {:?}", i_d, eq_d, n_d, p, u);
+            self.distributions = distributions;
+            self.distributions_buffer = Array4D::new(dimensions);
+        }
+        if propagation == Propagation::InPlace {
+            // The first in place step starts from streamed populations
+            let mut buffer = std::mem::take(&mut self.distributions_buffer);
+            self.stream(&self.distributions, &self.pressure, &mut buffer);
+            self.distributions = buffer;
+            self.odd_step = false;
         }
+        self.propagation = propagation;
+        self.set_layout(propagation.layout());
     }
 
-    pub fn apply_bounce_back(&mut self, coord: &Coord<3>) {
-        let mut new_q = [0.0; 27];
-        for q_i in 0..27 {
-            let q = self.distributions.get_q(coord, q_i);
-            new_q[D3Q27_OPP[q_i as usize]] = q;
+    pub fn propagation(&self) -> Propagation {
+        self.propagation
+    }
+
+    /// Reorder the populations in memory. `set_propagation` picks the layout of
+    /// its kernels, the results do not depend on it.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.distributions.set_layout(layout);
+        if self.propagation != Propagation::InPlace {
+            self.distributions_buffer.set_layout(layout);
         }
-        for q_i in 0..27 {
-            self.distributions.set_q(coord, q_i, new_q[q_i as usize]);
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.distributions.layout()
+    }
+
+    /// Keep the populations in `BlockSparse` tiles, allocated only where there is
+    /// fluid, instead of the whole grid. For mostly solid geometries such as porous
+    /// media. Sparse populations advance with `Propagation::Fused` through `step`,
+    /// set this after initializing and after the geometry, which can't change once
+    /// tiled. Only the populations are tiled: density, velocity and node types stay dense, four values and a byte per
+    /// node of the grid against `2 Q` values per node of a tile, as do force, eddy
+    /// viscosity and scalar fields.
+    pub fn set_sparse(&mut self, sparse: bool) {
+        if sparse == self.sparse.is_some() {
+            return;
         }
+        let q_bounds = nalgebra::matrix![0, V::Q as i32 - 1];
+        #[allow(clippy::toplevel_ref_arg)]
+        let dimensions = nalgebra::stack![self.grid_dimensions; q_bounds];
+        if sparse {
+            assert!(self.indirect.is_none(), "populations are either sparse or indirect");
+            self.set_propagation(Propagation::Fused);
+            let mut populations = BlockSparse::new(&self.node_types, V::Q);
+            for coord in coord_iter(self.grid_dimensions) {
+                for q_i in 0..V::Q {
+                    populations.set_q(&coord, q_i as i32, self.distributions.get_q(&coord, q_i as i32));
+                }
+            }
+            self.sparse = Some((populations.clone(), populations));
+            self.distributions = Array4D::default();
+            self.distributions_buffer = Array4D::default();
+        } else {
+            let (populations, _) = self.sparse.take().unwrap();
+            self.distributions = Array4D::with_layout(dimensions, Propagation::Fused.layout());
+            self.distributions_buffer = Array4D::with_layout(dimensions, Propagation::Fused.layout());
+            for coord in coord_iter(self.grid_dimensions) {
+                for q_i in 0..V::Q {
+                    self.distributions.set_q(&coord, q_i as i32, populations.get_q(&coord, q_i as i32));
+                }
+            }
+        }
+    }
+
+    /// Populations of `set_sparse`
+    pub fn sparse(&self) -> Option<&BlockSparse<T>> {
+        self.sparse.as_ref().map(|(populations, _)| populations)
     }
-/*
-    pub fn apply_inflow_bc(&mut self, coord &Coord<3>) {
-        for q_i in 0..27 {
-            let t = self.inflow_density * self.inflow_accel * D3Q27_W[q_i];
+
+    /// Keep the populations of the non solid nodes only, in a `FluidNodes` list
+    /// with the index each link pulls from, instead of the whole grid. Like
+    /// `set_sparse` they advance with `Propagation::Fused` through `step`, set this
+    /// after initializing, the geometry and the periodic axes, which can't change
+    /// once listed. `population` and `write_vtk` read through the list.
+    pub fn set_indirect(&mut self, indirect: bool) {
+        if indirect == self.indirect.is_some() {
+            return;
+        }
+        if indirect {
+            assert!(self.sparse.is_none(), "populations are either sparse or indirect");
+            self.set_propagation(Propagation::Fused);
+            let mut populations = FluidNodes::new::<V>(&self.node_types, &self.periodic);
+            populations.from_dense(&self.distributions);
+            self.indirect = Some((populations.clone(), populations));
+            self.distributions = Array4D::default();
+            self.distributions_buffer = Array4D::default();
+        } else {
+            let (populations, _) = self.indirect.take().unwrap();
+            self.distributions = populations.to_dense(Propagation::Fused.layout());
+            self.distributions_buffer = Array4D::with_layout(*self.distributions.dimensions(), self.layout());
         }
+    }
+
+    /// Populations of `set_indirect`
+    pub fn indirect(&self) -> Option<&FluidNodes<T>> {
+        self.indirect.as_ref().map(|(populations, _)| populations)
+    }
+
+    /// Whether the populations are dense `Array4D`s
+    fn dense(&self) -> bool {
+        self.sparse.is_none() && self.indirect.is_none()
+    }
 
+    /// Advance by one time step. The fused propagations give the same moments as
+    /// `Split`, but need a single component without Shan-Chen forces and no open
+    /// boundaries, since those read their neighbors after streaming.
+    pub fn step(&mut self) {
+        if self.propagation == Propagation::Split {
+            self.streaming();
+            self.apply_bcs();
+            self.moments();
+            self.collision();
+            return;
+        }
+        assert!(self.fusable(), "{:?} propagation does not support this case", self.propagation);
 
+        // The buoyancy in the fused sweep needs the temperature first
+        if let Some(scalar) = &mut self.scalar {
+            scalar.stream(&self.node_types, &self.periodic);
+            scalar.moments(&self.node_types);
+        }
+        match self.propagation {
+            Propagation::Fused if self.sparse.is_some() => self.sparse_step(),
+            Propagation::Fused if self.indirect.is_some() => self.indirect_step(),
+            Propagation::Fused => self.fused_step(),
+            Propagation::InPlace => {
+                self.in_place_step();
+                self.odd_step = !self.odd_step;
+            }
+            Propagation::Split => unreachable!(),
+        }
+        if let Some(scalar) = &mut self.scalar {
+            scalar.collide(&self.velocity, &self.node_types);
+        }
     }
 
-    pub fn apply_outflow_bc(&mut self, coord: &Coord<3>) {
-        for q_i in 0..27 {
-            let t = self.inflow_density * self.inflow_accel * D3Q27_W[q_i];
-  k      }
+    fn fusable(&self) -> bool {
+        self.shan_chen.is_none()
+            && self.components.is_empty()
+            && !self.boundaries.assignments().iter().any(|(_, boundary)| matches!(boundary, BoundaryType::Open))
     }
-*/
-    pub fn apply_bcs(&mut self) {
-        let x_min = self.grid_dimensions[(0, 0)];
-        let x_max = self.grid_dimensions[(0, 1)];
-        let y_min = self.grid_dimensions[(1, 0)];
-        let y_max = self.grid_dimensions[(1, 1)];
-        let z_min = self.grid_dimensions[(2, 0)];
-        let z_max = self.grid_dimensions[(2, 1)];
 
-        // Bottom
-        let bb_0 = matrix![x_min, x_max; y_min, y_max; z_min, z_max;];
-        for coord in coord_iter(bb_0) {
-            self.apply_bounce_back(&coord);
+    /// Post-collision population `q_i` of the last step, whatever the propagation
+    pub fn population(&self, coord: &Coord<3>, q_i: usize) -> T {
+        let shift = if self.shifted { self.weights[q_i] } else { T::zero() };
+        self.stored_population(coord, q_i) + shift
+    }
+
+    /// Density, velocity and non-equilibrium part of the pre-collision populations
+    /// at `coord`, between `moments` and `collision` of a split step. What the
+    /// levels of a `RefinedSolver` exchange.
+    pub(crate) fn node_state(&self, coord: &Coord<3>) -> NodeState<T> {
+        let (rho, u) = (self.pressure.get(coord), self.velocity.get(coord));
+        let neq = (0..V::Q)
+            .map(|q_i| {
+                let f_eq = equilibrium(self.weights[q_i], rho, self.directions[q_i].dot(&u), u.dot(&u), self.c_sqr);
+                self.population(coord, q_i) - f_eq
+            })
+            .collect();
+        NodeState { rho, u, neq }
+    }
+
+    /// Post-collision populations at `coord`, collided from `state` with its
+    /// non-equilibrium part scaled by `scale`, the inverse of `node_state`
+    pub(crate) fn set_node_state(&mut self, coord: &Coord<3>, state: &NodeState<T>, scale: T) {
+        let streamed = self.dense() && self.propagation != Propagation::InPlace;
+        assert!(streamed, "node states need dense, streamed populations");
+        let (rho, u) = (state.rho, state.u);
+        let omega = self.collision.omega();
+        let forcing = T::one() - T::of(0.5) * omega;
+        let force = self.total_force(coord, rho);
+        for q_i in 0..V::Q {
+            let w_i = self.weights[q_i];
+            let c = &self.directions[q_i];
+            let f_eq = equilibrium(w_i, rho, c.dot(&u), u.dot(&u), self.c_sqr);
+            let source = force.map_or(T::zero(), |force| forcing * guo_source(w_i, c, &u, &force, self.c_sqr));
+            let shift = if self.shifted { w_i } else { T::zero() };
+            let neq = (T::one() - omega) * scale * state.neq[q_i];
+            self.distributions.set_q(coord, q_i as i32, f_eq + neq + source - shift);
         }
+        self.pressure.set(coord, rho);
+        self.velocity.set(coord, u);
+    }
 
-        // Top
-        let bb_1 = matrix![x_min, x_max; y_max, y_max; z_min, z_max;];
-        for coord in coord_iter(bb_1) {
-            self.apply_bounce_back(&coord);
+    /// `population` as stored, see `set_shifted`
+    fn stored_population(&self, coord: &Coord<3>, q_i: usize) -> T {
+        if let Some((sparse, _)) = &self.sparse {
+            return sparse.get_q(coord, q_i as i32);
+        }
+        if let Some((indirect, _)) = &self.indirect {
+            return indirect.get_q(coord, q_i as i32);
+        }
+        let opposite = V::opposites()[q_i];
+        if self.propagation != Propagation::InPlace || self.node_types.get(coord) == NodeType::Solid {
+            return self.distributions.get_q(coord, q_i as i32);
+        }
+        if self.odd_step {
+            return self.distributions.get_q(coord, opposite as i32);
+        }
+        // Streamed already, undo the push of the odd step
+        let (neighbor, in_domain, fluid) = self.link(coord, self.offsets[q_i]);
+        if fluid {
+            self.distributions.get_q(&neighbor, q_i as i32)
+        } else {
+            let wall = self.bounce_back(T::zero(), &neighbor, in_domain, opposite, self.pressure.get(coord));
+            self.distributions.get_q(coord, opposite as i32) - wall
         }
+    }
 
-        // Left (x_min)
-        let bb_2 = matrix![x_min, x_min; y_min + 1, y_max - 1; z_min, z_max;];
-        for coord in coord_iter(bb_2) {
-            self.apply_bounce_back(&coord);
+    /// Boundary condition, moments and collision of a node from its streamed
+    /// populations `f`, as in the split kernels. Leaves the post-collision
+    /// populations in `relaxed`, returns the density, velocity and eddy viscosity.
+    fn relax_node(
+        &self,
+        coord: &Coord<3>,
+        f: &mut [T],
+        f_eq: &mut [T],
+        source: &mut [T],
+        relaxed: &mut [T],
+    ) -> (T, Vec3<T>, Option<T>) {
+        if self.node_types.get(coord) == NodeType::Boundary {
+            self.boundary_condition(self.flags.get(coord) as usize - 1, coord, f, true);
         }
 
-        // Right
-        let bb_3 = matrix![x_max, x_max; y_min + 1, y_max - 1; z_min, z_max;];
-        for coord in coord_iter(bb_3) {
-            self.apply_bounce_back(&coord);
+        let mut p = T::zero();
+        let mut u = Vec3::zero();
+        for (q, direction) in f.iter().zip(&self.directions) {
+            p += *q;
+            u += direction * *q;
+        }
+        if self.shifted {
+            p += T::one();
+        }
+        let force = self.total_force(coord, p);
+        if let Some(force) = force {
+            u += force * T::of(0.5);
+        }
+        if p.abs() > T::of(0.00001) {
+            u /= p;
         }
 
-        // front
-        let bb_4 = matrix![x_min + 1, x_max - 1; y_min + 1, y_max - 1;  z_min, z_min];
-        for coord in coord_iter(bb_4) {
-            self.apply_bounce_back(&coord);
+        let u_sqr = u.dot(&u);
+        for q_i in 0..V::Q {
+            let dir_u = self.directions[q_i].dot(&u);
+            let w_i = self.weights[q_i];
+            f_eq[q_i] = self.stored_equilibrium(w_i, p, dir_u, u_sqr);
+            source[q_i] = match &force {
+                Some(force) => guo_source(w_i, &self.directions[q_i], &u, force, self.c_sqr),
+                None => T::zero(),
+            };
+        }
+        let tau_0 = self.collision.omega().recip();
+        let nu = self.smagorinsky.map(|model| {
+            let stress = non_equilibrium_stress(&self.directions, f, f_eq);
+            let tau = model.relaxation_time(tau_0, &stress, p, self.c_sqr);
+            self.c_sqr * (tau - tau_0)
+        });
+        let unshift = self.unshift_for_collision();
+        if unshift {
+            shift_by_weights::<V, T>(f, T::one());
+            shift_by_weights::<V, T>(f_eq, T::one());
+        }
+        let node = CollisionNode {
+            rho: p,
+            u,
+            force: force.unwrap_or_else(Vec3::zero),
+            f,
+            f_eq,
+            source,
+            omega: nu.map(|nu| (tau_0 + nu / self.c_sqr).recip()),
+        };
+        self.collision.collide(&node, relaxed);
+        if unshift {
+            shift_by_weights::<V, T>(relaxed, -T::one());
         }
+        (p, u, nu)
+    }
 
-        let bb_5 = matrix![x_min + 1, x_max - 1; y_min + 1, y_max - 1; z_max, z_max];
-        for coord in coord_iter(bb_5) {
-            self.apply_bounce_back(&coord);
+    fn take_moments(&mut self) -> MomentBuffers<T> {
+        MomentBuffers {
+            pressure: std::mem::take(&mut self.pressure.buffer),
+            velocity: std::mem::take(&mut self.velocity.buffer),
+            eddy_viscosity: self.eddy_viscosity.as_mut().map(|nu| std::mem::take(&mut nu.buffer)),
         }
+    }
 
-        /*
-        // Inflow,
-        let mut inflow_face = self.grid_dimensions;
-        inflow_face[(2, 1)] = inflow_face[(2, 0)];
+    fn restore_moments(&mut self, moments: MomentBuffers<T>) {
+        self.pressure.buffer = moments.pressure;
+        self.velocity.buffer = moments.velocity;
+        if let (Some(nu), Some(buffer)) = (&mut self.eddy_viscosity, moments.eddy_viscosity) {
+            nu.buffer = buffer;
+        }
+    }
 
-        // Outlfow
-        let mut outflow_face = self.grid_dimensions;
-        outflow_face[(2, 0)] = inflow_face[(2, 1)];
+    /// Run `relax_node` on every non solid node of `nodes`. `pull` gathers the
+    /// streamed populations of a node given its linear index and last density,
+    /// `push` stores the relaxed ones given the new density.
+    fn fused_sweep<P, W>(&self, moments: &mut MomentBuffers<T>, nodes: SweepNodes<T>, pull: P, push: W)
+    where
+        P: Fn(&Coord<3>, usize, T, &mut [T]) + Sync,
+        W: Fn(&Coord<3>, usize, T, &[T]) + Sync,
+    {
+        let pressure = Disjoint::new(&mut moments.pressure);
+        let velocity = Disjoint::new(&mut moments.velocity);
+        let eddy_viscosity = moments.eddy_viscosity.as_deref_mut().map(Disjoint::new);
+        let scratch = || (vec![T::zero(); V::Q], vec![T::zero(); V::Q], vec![T::zero(); V::Q], vec![T::zero(); V::Q]);
+        let kernel = |(f, f_eq, source, relaxed): &mut (Vec<T>, Vec<T>, Vec<T>, Vec<T>), coord| {
+            if self.node_types.get(&coord) == NodeType::Solid {
+                return;
+            }
+            let node = coord_to_linear_in_box(&coord, &self.grid_dimensions);
+            // SAFETY: a node only touches its own moments
+            pull(&coord, node, unsafe { pressure.get(node) }, f);
+            let (rho, u, nu) = self.relax_node(&coord, f, f_eq, source, relaxed);
+            unsafe {
+                pressure.set(node, rho);
+                velocity.set(node, u);
+                if let (Some(eddy_viscosity), Some(nu)) = (&eddy_viscosity, nu) {
+                    eddy_viscosity.set(node, nu);
+                }
+            }
+            push(&coord, node, rho, relaxed);
+        };
+        match nodes {
+            SweepNodes::Grid => for_each_coord(&self.grid_dimensions, scratch, kernel),
+            SweepNodes::Tiles(sparse) => for_each_tile_coord(sparse, scratch, kernel),
+            SweepNodes::Listed(indirect) => for_each_listed_coord(indirect.nodes(), scratch, kernel),
+        }
+    }
 
-        */
-        /*
-        // Bounce back
-        let mut bb_0 = self.grid_dimensions;
-        bb_0[(0, 1)] = bb_0[(0, 0)];
-        for coord in coord_iter(bb_0) {
-            println!("bb_coord: {:?}", coord);
-            self.apply_bounce_back(&coord);
+    /// Fused sweep from `distributions` into `distributions_buffer`
+    fn fused_step(&mut self) {
+        let mut target = std::mem::take(&mut self.distributions_buffer.buffer);
+        let mut moments = self.take_moments();
+        {
+            let target = Disjoint::new(&mut target);
+            self.fused_sweep(
+                &mut moments,
+                SweepNodes::Grid,
+                |coord, _, rho, f| {
+                    for (q_i, q) in f.iter_mut().enumerate() {
+                        *q = self.pull(&self.distributions, coord, q_i, rho);
+                    }
+                },
+                |_, node, _, relaxed| {
+                    for (q_i, q) in relaxed.iter().enumerate() {
+                        // SAFETY: the populations of a node in the target are its own
+                        unsafe { target.set(self.distributions_buffer.buffer_index(node, q_i), *q) };
+                    }
+                },
+            );
         }
+        self.distributions_buffer.buffer = target;
+        self.restore_moments(moments);
+        std::mem::swap(&mut self.distributions, &mut self.distributions_buffer);
+    }
+
+    /// Fused sweep over the allocated tiles of the sparse populations
+    fn sparse_step(&mut self) {
+        let (source, mut target) = self.sparse.take().unwrap();
+        let mut moments = self.take_moments();
+        {
+            let populations = Disjoint::new(&mut target.buffer);
+            self.fused_sweep(
+                &mut moments,
+                SweepNodes::Tiles(&source),
+                |coord, _, rho, f| {
+                    let tile = source.tile(coord).unwrap();
+                    for (q_i, q) in f.iter_mut().enumerate() {
+                        *q = self.pull_sparse(&source, tile, coord, q_i, rho);
+                    }
+                },
+                |coord, _, _, relaxed| {
+                    // Both buffers have the same tiles
+                    let tile = source.tile(coord).unwrap();
+                    for (q_i, q) in relaxed.iter().enumerate() {
+                        // SAFETY: the populations of a node in the target are its own
+                        unsafe { populations.set(source.index(tile, coord, q_i), *q) };
+                    }
+                },
+            );
+        }
+        self.restore_moments(moments);
+        self.sparse = Some((target, source));
+    }
+
+    /// Fused sweep over the listed fluid nodes, pulling through their link table.
+    /// Links that bounce back read the node itself and add the moving wall term.
+    fn indirect_step(&mut self) {
+        let (source, mut target) = self.indirect.take().unwrap();
+        let mut moments = self.take_moments();
+        {
+            let populations = Disjoint::new(&mut target.buffer);
+            self.fused_sweep(
+                &mut moments,
+                SweepNodes::Listed(&source),
+                |coord, _, rho, f| {
+                    let position = source.position(coord).unwrap();
+                    for (q_i, q) in f.iter_mut().enumerate() {
+                        let index = source.pull_index(position, q_i);
+                        *q = source.buffer[index];
+                        if index == source.index(position, V::opposites()[q_i]) {
+                            let (neighbor, in_domain, _) = self.link(coord, -self.offsets[q_i]);
+                            *q = self.bounce_back(*q, &neighbor, in_domain, q_i, rho);
+                        }
+                    }
+                },
+                |coord, _, _, relaxed| {
+                    // Both lists have the same nodes
+                    let position = source.position(coord).unwrap();
+                    for (q_i, q) in relaxed.iter().enumerate() {
+                        // SAFETY: the populations of a node in the target are its own
+                        unsafe { populations.set(source.index(position, q_i), *q) };
+                    }
+                },
+            );
+        }
+        self.restore_moments(moments);
+        self.indirect = Some((target, source));
+    }
+
+    /// Fused sweep on `distributions` alone with the AA pattern. A population slot
+    /// is only ever read and written by one node, the one it streams into on odd
+    /// steps, so the nodes can still run in parallel.
+    fn in_place_step(&mut self) {
+        let mut buffer = std::mem::take(&mut self.distributions.buffer);
+        let mut moments = self.take_moments();
+        {
+            let populations = Disjoint::new(&mut buffer);
+            let index = |coord: &Coord<3>, q_i: usize| {
+                let node = coord_to_linear_in_box(coord, &self.grid_dimensions);
+                self.distributions.buffer_index(node, q_i)
+            };
+            // SAFETY: each slot belongs to a single node, see above
+            if self.odd_step {
+                self.fused_sweep(
+                    &mut moments,
+                    SweepNodes::Grid,
+                    |coord, _, rho, f| {
+                        for (q_i, q) in f.iter_mut().enumerate() {
+                            let (neighbor, in_domain, fluid) = self.link(coord, -self.offsets[q_i]);
+                            *q = if fluid {
+                                unsafe { populations.get(index(&neighbor, V::opposites()[q_i])) }
+                            } else {
+                                let reflected = unsafe { populations.get(index(coord, q_i)) };
+                                self.bounce_back(reflected, &neighbor, in_domain, q_i, rho)
+                            };
+                        }
+                    },
+                    |coord, _, rho, relaxed| {
+                        for (q_i, q) in relaxed.iter().enumerate() {
+                            let (neighbor, in_domain, fluid) = self.link(coord, self.offsets[q_i]);
+                            let opposite = V::opposites()[q_i];
+                            if fluid {
+                                unsafe { populations.set(index(&neighbor, q_i), *q) };
+                            } else {
+                                let q = self.bounce_back(*q, &neighbor, in_domain, opposite, rho);
+                                unsafe { populations.set(index(coord, opposite), q) };
+                            }
+                        }
+                    },
+                );
+            } else {
+                self.fused_sweep(
+                    &mut moments,
+                    SweepNodes::Grid,
+                    |_, node, _, f| {
+                        for (q_i, q) in f.iter_mut().enumerate() {
+                            *q = unsafe { populations.get(self.distributions.buffer_index(node, q_i)) };
+                        }
+                    },
+                    |_, node, _, relaxed| {
+                        for (q_i, q) in relaxed.iter().enumerate() {
+                            let opposite = V::opposites()[q_i];
+                            unsafe { populations.set(self.distributions.buffer_index(node, opposite), *q) };
+                        }
+                    },
+                );
+            }
+        }
+        self.distributions.buffer = buffer;
+        self.restore_moments(moments);
+    }
+
+    pub fn apply_bounce_back(&mut self, coord: &Coord<3>) {
+        let mut new_q = vec![T::zero(); V::Q];
+        for q_i in 0..V::Q {
+            let q = self.distributions.get_q(coord, q_i as i32);
+            new_q[V::opposites()[q_i]] = q;
+        }
+        for (q_i, q) in new_q.into_iter().enumerate() {
+            self.distributions.set_q(coord, q_i as i32, q);
+        }
+    }
+
+    pub fn add_velocity_inlet(&mut self, face: Face, profile: VelocityProfile<T>) {
+        self.boundaries.set_face(face, BoundaryType::VelocityInlet(profile));
+        self.resolve_boundaries();
+    }
+
+    pub fn add_pressure_outlet(&mut self, face: Face, density: T) {
+        self.boundaries.set_face(face, BoundaryType::PressureOutlet { density });
+        self.resolve_boundaries();
+    }
+
+    /// Applied to the streamed populations, before `moments`.
+    /// Each boundary node is handled by the single assignment that won it in
+    /// `BoundaryRegistry::resolve`. Additional components see the same velocity
+    /// inlets, pressure outlets only set the density of component 0 and are open
+    /// for the others.
+    pub fn apply_bcs(&mut self) {
+        let mut distributions = std::mem::take(&mut self.distributions);
+        self.apply_bcs_to(&mut distributions, true);
+        self.distributions = distributions;
 
-        let mut bb_1 = self.grid_dimensions;
-        bb_1[(0, 0)] = bb_0[(0, 1)];
-        for coord in coord_iter(bb_1) {
-            println!("bb_coord: {:?}", coord);
-            self.apply_bounce_back(&coord);
+        let mut components = std::mem::take(&mut self.components);
+        for component in &mut components {
+            self.apply_bcs_to(&mut component.distributions, false);
         }
+        self.components = components;
+    }
+
+    fn apply_bcs_to(&self, distributions: &mut Array4D<T>, primary: bool) {
+        for (index, nodes) in self.boundary_nodes.iter().enumerate() {
+            let facing = self.boundaries.facing(index, &self.grid_dimensions);
+            let normal = facing.map(|face| face.normal()).unwrap_or_else(Coord::zero);
 
-        let mut bb_2 = self.grid_dimensions;
-        bb_2[(1, 1)] = bb_2[(1, 0)];
-        for coord in coord_iter(bb_2) {
-            println!("bb_coord: {:?}", coord);
-            self.apply_bounce_back(&coord);
+            let updated = map_nodes(nodes, || vec![T::zero(); V::Q], |interior, coord| {
+                let mut f: Vec<T> = (0..V::Q).map(|q_i| distributions.get_q(coord, q_i as i32)).collect();
+                if !self.boundary_condition(index, coord, &mut f, primary) {
+                    let neighbor = coord - normal;
+                    for (q_i, q) in interior.iter_mut().enumerate() {
+                        *q = distributions.get_q(&neighbor, q_i as i32);
+                    }
+                    zero_gradient::<V, T>(&mut f, interior, &normal);
+                }
+                f
+            });
+            for (coord, f) in nodes.iter().zip(updated) {
+                for (q_i, q) in f.iter().enumerate() {
+                    distributions.set_q(coord, q_i as i32, *q);
+                }
+            }
         }
+    }
 
-        let mut bb_3 = self.grid_dimensions;
-        bb_3[(1, 0)] = bb_3[(1, 1)];
-        for coord in coord_iter(bb_3) {
-            println!("bb_coord: {:?}", coord);
-            self.apply_bounce_back(&coord);
+    /// Condition of assignment `index` on the streamed populations `f` of the
+    /// boundary node `coord`. Returns `false` for open boundaries, which copy from
+    /// the interior instead.
+    fn boundary_condition(&self, index: usize, coord: &Coord<3>, f: &mut [T], primary: bool) -> bool {
+        let (target, boundary) = &self.boundaries.assignments()[index];
+        let facing = self.boundaries.facing(index, &self.grid_dimensions);
+        let normal = facing.map(|face| face.normal()).unwrap_or_else(Coord::zero);
+        let open = matches!(boundary, BoundaryType::PressureOutlet { .. } if !primary);
+        if open || matches!(boundary, BoundaryType::Open) {
+            return false;
         }
-        */
+        // The conditions work on the populations themselves
+        let shifted = self.shifted && primary;
+        if shifted {
+            shift_by_weights::<V, T>(f, T::one());
+        }
+        match boundary {
+            BoundaryType::VelocityInlet(profile) => {
+                let u = profile.velocity(facing.unwrap(), &target.aabb(&self.grid_dimensions), coord);
+                zou_he_velocity::<V, T>(f, &normal, &u);
+            }
+            BoundaryType::PressureOutlet { density } if primary => {
+                zou_he_pressure::<V, T>(f, &normal, *density);
+            }
+            BoundaryType::Symmetry => {
+                symmetry::<V, T>(f, &normal);
+            }
+            _ => unreachable!(),
+        }
+        if shifted {
+            shift_by_weights::<V, T>(f, -T::one());
+        }
+        true
     }
 
-    pub fn write_vtk(&self, i: usize) {
+    pub fn write_vtk(&self, i: usize)
+    where
+        IOBuffer: From<Vec<T>>,
+    {
         let buffer_size = box_buffer_size(&self.grid_dimensions);
-        //let distributions = vec![vec![0.0; buffer_size]; 27];
         let mut density = Vec::with_capacity(buffer_size);
+        let mut node_type = Vec::with_capacity(buffer_size);
         let mut velocity = Vec::with_capacity(3 * buffer_size);
         let mut points = Vec::with_capacity(3 * buffer_size);
-        let mut qs = vec![Vec::with_capacity(buffer_size); 27];
+        let mut qs: Vec<Vec<T>> = (0..V::Q).map(|_| Vec::with_capacity(buffer_size)).collect();
         for coord in coord_iter(self.grid_dimensions) {
-            points.push(coord[0] as f32);
-            points.push(coord[1] as f32);
-            points.push(coord[2] as f32);
+            points.push(T::of(coord[0] as f64));
+            points.push(T::of(coord[1] as f64));
+            points.push(T::of(coord[2] as f64));
 
             density.push(self.pressure.get(&coord));
+            node_type.push(self.node_types.get(&coord) as u8);
 
             let vel = self.velocity.get(&coord);
             velocity.push(vel[0]);
             velocity.push(vel[1]);
             velocity.push(vel[2]);
 
-            for q_i in 0..27 {
-                let q = self.distributions.get_q(&coord, q_i);
-                qs[q_i as usize].push(q);
+            for (q_i, q_buffer) in qs.iter_mut().enumerate() {
+                q_buffer.push(self.population(&coord, q_i));
             }
         }
 
@@ -330,31 +1582,75 @@ impl Solver {
 
         let mut point_attributes = vec![
             Attribute::DataArray(DataArrayBase {
-                name: format!("density"),
+                name: "density".to_string(),
                 elem: ElementType::Scalars {
                     num_comp: 1,
                     lookup_table: None,
                 },
-                data: IOBuffer::F32(density),
+                data: IOBuffer::from(density),
             }),
             Attribute::DataArray(DataArrayBase {
-                name: format!("velocity"),
+                name: "velocity".to_string(),
                 elem: ElementType::Scalars {
                     num_comp: 3,
                     lookup_table: None,
                 },
-                data: IOBuffer::F32(velocity),
+                data: IOBuffer::from(velocity),
+            }),
+            Attribute::DataArray(DataArrayBase {
+                name: "node_type".to_string(),
+                elem: ElementType::Scalars {
+                    num_comp: 1,
+                    lookup_table: None,
+                },
+                data: IOBuffer::U8(node_type),
             }),
         ];
 
-        for q_i in 0..27 {
+        for (index, component) in self.components.iter().enumerate() {
+            let data: Vec<T> = coord_iter(self.grid_dimensions).map(|coord| component.density.get(&coord)).collect();
+            point_attributes.push(Attribute::DataArray(DataArrayBase {
+                name: format!("density_{}", index + 1),
+                elem: ElementType::Scalars {
+                    num_comp: 1,
+                    lookup_table: None,
+                },
+                data: IOBuffer::from(data),
+            }));
+        }
+
+        if let Some(scalar) = &self.scalar {
+            let data: Vec<T> = coord_iter(self.grid_dimensions).map(|coord| scalar.value().get(&coord)).collect();
+            point_attributes.push(Attribute::DataArray(DataArrayBase {
+                name: scalar.name.clone(),
+                elem: ElementType::Scalars {
+                    num_comp: 1,
+                    lookup_table: None,
+                },
+                data: IOBuffer::from(data),
+            }));
+        }
+
+        if let Some(eddy_viscosity) = &self.eddy_viscosity {
+            let data: Vec<T> = coord_iter(self.grid_dimensions).map(|coord| eddy_viscosity.get(&coord)).collect();
+            point_attributes.push(Attribute::DataArray(DataArrayBase {
+                name: "eddy_viscosity".to_string(),
+                elem: ElementType::Scalars {
+                    num_comp: 1,
+                    lookup_table: None,
+                },
+                data: IOBuffer::from(data),
+            }));
+        }
+
+        for (q_i, q_buffer) in qs.into_iter().enumerate() {
             point_attributes.push(Attribute::DataArray(DataArrayBase {
                 name: format!("q_{}", q_i),
                 elem: ElementType::Scalars {
                     num_comp: 1,
                     lookup_table: None,
                 },
-                data: IOBuffer::F32(qs[q_i].clone()),
+                data: IOBuffer::from(q_buffer),
             }));
         }
 
@@ -364,7 +1660,7 @@ impl Solver {
             byte_order: ByteOrder::LittleEndian,
             file_path: None,
             data: DataSet::inline(UnstructuredGridPiece {
-                points: IOBuffer::F32(points),
+                points: IOBuffer::from(points),
                 cells: Cells {
                     cell_verts: VertexNumbers::XML {
                         connectivity,
@@ -382,3 +1678,703 @@ impl Solver {
         .unwrap();
     }
 }
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::matrix;
+
+    fn total_mass<V: VelocitySet>(solver: &Solver<V>) -> f32 {
+        solver.distributions.buffer.iter().sum()
+    }
+
+    #[test]
+    fn periodic_streaming_conserves_mass() {
+        let mut solver = Solver::<D3Q19>::new(matrix![0, 5; 0, 4; 0, 3], 1.0, 1.0, 0.0);
+        for axis in 0..3 {
+            solver.set_periodic(axis, true);
+        }
+        for coord in coord_iter(solver.grid_dimensions) {
+            for q_i in 0..D3Q19::Q {
+                let value = (coord[0] + 2 * coord[1] + 3 * coord[2] + q_i as i32) as f32;
+                solver.distributions.set_q(&coord, q_i as i32, value);
+            }
+        }
+
+        let before = total_mass(&solver);
+        solver.streaming();
+        assert_eq!(before, total_mass(&solver));
+
+        // A population leaving through x_max reenters at x_min
+        let value = solver.distributions.get_q(&vector![0, 2, 2], 1);
+        assert_eq!(value, (5 + 2 * 2 + 3 * 2 + 1) as f32);
+    }
+
+    #[test]
+    fn halfway_bounce_back_conserves_mass() {
+        let mut solver = Solver::<D3Q27>::new(matrix![0, 9; 0, 9; 0, 9], 1.2, 1.0, 0.0);
+        solver.add_obstacle(Obstacle::Sphere {
+            center: vector![4.5, 4.5, 4.5],
+            radius: 2.0,
+        });
+        // Walls on every face plus the 32 nodes of the sphere
+        assert_eq!(solver.node_types.count(NodeType::Solid), 10 * 10 * 10 - 8 * 8 * 8 + 32);
+
+        let u = vector![0.05, 0.02, -0.01];
+        for coord in coord_iter(solver.grid_dimensions) {
+            if solver.node_types.get(&coord) == NodeType::Solid {
+                continue;
+            }
+            for (q_i, w_i) in D3Q27_W.iter().enumerate() {
+                let c_u = solver.directions[q_i].dot(&u);
+                let value = equilibrium(*w_i as f32, 1.0, c_u, u.dot(&u), solver.c_sqr);
+                solver.distributions.set_q(&coord, q_i as i32, value);
+            }
+        }
+
+        let before = total_mass(&solver);
+        for _ in 0..10 {
+            solver.streaming();
+            solver.apply_bcs();
+            solver.moments();
+            solver.collision();
+        }
+        assert!((before - total_mass(&solver)).abs() / before < 1e-5);
+    }
+
+    #[test]
+    fn couette_flow() {
+        // Walls at y = 0 and y = 11, the halfway walls sit at 0.5 and 10.5
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 3; 0, 11; 0, 0], 1.0, 1.0, 0.0);
+        solver.set_periodic(0, true);
+        solver.set_periodic(2, true);
+        let u_w = 0.05;
+        solver.set_moving_wall(Face::YMax, vector![u_w, 0.0, 0.0]);
+        solver.equilibrium_init();
+        solver.moments();
+        for _ in 0..4000 {
+            solver.streaming();
+            solver.apply_bcs();
+            solver.moments();
+            solver.collision();
+        }
+
+        for y in 1..=10 {
+            let expected = u_w * (y as f32 - 0.5) / 10.0;
+            let u = solver.velocity().get(&vector![1, y, 0]);
+            assert!((u[0] - expected).abs() < 1e-4, "y: {}, u: {}, expected: {}", y, u[0], expected);
+            assert!(u[1].abs() < 1e-6);
+        }
+    }
+
+    /// Gravity driven channel flow between walls at y = 0 and y = 11, the halfway
+    /// walls sit at 0.5 and 10.5. `omega` is the rate that sets the viscosity,
+    /// `tolerance` is relative to the peak velocity.
+    fn check_poiseuille<V: VelocitySet, T: NumTrait>(mut solver: Solver<V, T>, omega: T, tolerance: T) {
+        solver.set_periodic(0, true);
+        solver.set_periodic(2, true);
+        let g = T::of(5e-5);
+        solver.set_body_force(BodyForce::Uniform(vector![g, T::zero(), T::zero()]));
+        solver.equilibrium_init();
+        solver.moments();
+        for _ in 0..1500 {
+            solver.streaming();
+            solver.apply_bcs();
+            solver.moments();
+            solver.collision();
+        }
+
+        let nu = T::of(V::c_sqr()) * (omega.recip() - T::of(0.5));
+        let u_max = g / (T::of(2.0) * nu) * T::of(25.0);
+        for y in 1..=10 {
+            let yf = T::of(y as f64);
+            let expected = g / (T::of(2.0) * nu) * (yf - T::of(0.5)) * (T::of(10.5) - yf);
+            let u = solver.velocity().get(&vector![1, y, 0]);
+            assert!((u[0] - expected).abs() < tolerance * u_max, "y: {}, u: {}, expected: {}", y, u[0], expected);
+            assert!(u[1].abs() < T::of(1e-6));
+        }
+    }
+
+    #[test]
+    fn gravity_driven_poiseuille() {
+        let omega = 1.0;
+        check_poiseuille(Solver::<D2Q9>::new(matrix![0, 3; 0, 11; 0, 0], omega, 1.0, 0.0), omega, 0.01);
+    }
+
+    #[test]
+    fn inflow_accel_is_a_body_force() {
+        let solver = Solver::<D2Q9>::new(matrix![0, 3; 0, 11; 0, 0], 1.0, 2.0, 1e-5);
+        assert!(matches!(solver.body_force(), Some(BodyForce::Uniform(force)) if *force == vector![2e-5, 0.0, 0.0]));
+        assert!(Solver::<D2Q9>::new(matrix![0, 3; 0, 11; 0, 0], 1.0, 1.0, 0.0).body_force().is_none());
+    }
+
+    #[test]
+    fn precisions_side_by_side() {
+        let single = Solver::<D2Q9, f32>::new(matrix![0, 3; 0, 11; 0, 0], 1.0, 1.0, 0.0);
+        let double = Solver::<D2Q9, f64>::new(matrix![0, 3; 0, 11; 0, 0], 1.0, 1.0, 0.0);
+        check_poiseuille(single, 1.0, 0.01);
+        check_poiseuille(double, 1.0, 0.01);
+    }
+
+    #[test]
+    fn mrt_poiseuille() {
+        // The shear rate alone sets the viscosity
+        let omega = 1.0;
+        let collision = Mrt::<D2Q9>::with_rates(omega, 1.4, 1.2);
+        check_poiseuille(Solver::with_collision(matrix![0, 3; 0, 11; 0, 0], collision, 1.0, 0.0), omega, 0.01);
+    }
+
+    #[test]
+    fn trt_magic_poiseuille() {
+        // With the magic parameter at 3 / 16 the walls sit exactly halfway even
+        // though BGK at this omega would slip
+        let omega = 0.8;
+        let collision = Trt::with_magic(omega, 3.0 / 16.0);
+        check_poiseuille(Solver::<D2Q9>::with_collision(matrix![0, 3; 0, 11; 0, 0], collision, 1.0, 0.0), omega, 0.001);
+    }
+
+    #[test]
+    fn cumulant_poiseuille() {
+        let omega = 1.0;
+        let collision = Cumulant {
+            shear: omega,
+            bulk: 1.2,
+            higher: 1.0,
+        };
+        // The higher order rates move the bounce-back wall, the profile slips by about 1%
+        check_poiseuille(Solver::with_collision(matrix![0, 3; 0, 11; 0, 0], collision, 1.0, 0.0), omega, 0.02);
+    }
+
+    #[test]
+    fn entropic_poiseuille() {
+        // Close to equilibrium the stabilizer stays at 2 and the flow matches BGK
+        let omega = 1.0;
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 3; 0, 11; 0, 0], omega, 1.0, 0.0);
+        solver.set_collision(Entropic { omega });
+        check_poiseuille(solver, omega, 0.01);
+    }
+
+    #[test]
+    fn smagorinsky_eddy_viscosity() {
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 3; 0, 11; 0, 0], 1.0, 1.0, 0.0);
+        solver.set_periodic(0, true);
+        solver.set_periodic(2, true);
+        let constant = 0.4;
+        solver.set_smagorinsky(Smagorinsky::new(constant));
+        solver.set_body_force(BodyForce::Uniform(vector![2e-4, 0.0, 0.0]));
+        solver.equilibrium_init();
+        solver.moments();
+        for _ in 0..1500 {
+            solver.streaming();
+            solver.apply_bcs();
+            solver.moments();
+            solver.collision();
+        }
+
+        // (C_s Delta)^2 |du / dy| against the shear from central differences
+        let eddy_viscosity = solver.eddy_viscosity().unwrap();
+        for y in 2..=9 {
+            let du_dy = (solver.velocity().get(&vector![1, y + 1, 0])[0] - solver.velocity().get(&vector![1, y - 1, 0])[0]) / 2.0;
+            let expected = constant * constant * du_dy.abs();
+            let nu_t = eddy_viscosity.get(&vector![1, y, 0]);
+            assert!((nu_t - expected).abs() < 0.05 * expected + 1e-7, "y: {}, nu_t: {}, expected: {}", y, nu_t, expected);
+        }
+    }
+
+    #[test]
+    fn shan_chen_phase_separation() {
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 31; 0, 0; 0, 0], 1.0, 1.0, 0.0);
+        for axis in 0..3 {
+            solver.set_periodic(axis, true);
+        }
+        solver.set_shan_chen(ShanChen {
+            potential: Pseudopotential::Exponential { rho_0: 1.0 },
+            g: -5.0,
+            g_wall: 0.0,
+        });
+
+        // A liquid slab in vapor, both away from coexistence
+        for coord in coord_iter(solver.grid_dimensions) {
+            let rho = if (8..24).contains(&coord[0]) { 1.5 } else { 0.5 };
+            for (q_i, w_i) in D2Q9_W.iter().enumerate() {
+                solver.distributions.set_q(&coord, q_i as i32, rho * *w_i as f32);
+            }
+        }
+        let before = total_mass(&solver);
+        solver.moments();
+        for _ in 0..2000 {
+            solver.streaming();
+            solver.apply_bcs();
+            solver.moments();
+            solver.collision();
+        }
+        assert!((before - total_mass(&solver)).abs() / before < 1e-4);
+
+        // Coexistence densities of G = -5 are about 1.9 and 0.16
+        let liquid = solver.density().get(&vector![15, 0, 0]);
+        let vapor = solver.density().get(&vector![0, 0, 0]);
+        assert!(liquid > 1.7, "liquid: {}", liquid);
+        assert!(vapor < 0.3, "vapor: {}", vapor);
+    }
+
+    #[test]
+    fn immiscible_components_stay_apart() {
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 31; 0, 0; 0, 0], 1.0, 1.0, 0.0);
+        for axis in 0..3 {
+            solver.set_periodic(axis, true);
+        }
+        let other = solver.add_component(0.8);
+        solver.set_component_coupling(ComponentCoupling::immiscible(3.0));
+
+        // A slab of component 0 in component 1, slightly mixed
+        for coord in coord_iter(solver.grid_dimensions) {
+            let (rho_0, rho_1) = if (8..24).contains(&coord[0]) { (0.9, 0.1) } else { (0.1, 0.9) };
+            for (q_i, w_i) in D2Q9_W.iter().enumerate() {
+                solver.distributions.set_q(&coord, q_i as i32, rho_0 * *w_i as f32);
+                solver.component_mut(other).distributions.set_q(&coord, q_i as i32, rho_1 * *w_i as f32);
+            }
+        }
+        let component_mass = |solver: &Solver<D2Q9>| solver.component(other).distributions.buffer.iter().sum::<f32>();
+        let before = (total_mass(&solver), component_mass(&solver));
+        solver.moments();
+        for _ in 0..2000 {
+            solver.streaming();
+            solver.apply_bcs();
+            solver.moments();
+            solver.collision();
+        }
+        assert!((before.0 - total_mass(&solver)).abs() / before.0 < 1e-4);
+        assert!((before.1 - component_mass(&solver)).abs() / before.1 < 1e-4);
+
+        // The repulsion pushes each component out of the other's bulk
+        let inside = vector![15, 0, 0];
+        let outside = vector![0, 0, 0];
+        assert!(solver.component(other).density().get(&inside) < 0.1);
+        assert!(solver.density().get(&outside) < 0.1);
+        assert!(solver.density().get(&inside) > 0.95);
+        assert!(solver.velocity().get(&inside).norm() < 1e-4);
+    }
+
+    #[test]
+    fn scalar_conduction() {
+        // Flux into the wall at y = 0, fixed at zero on the wall at y = 11
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 3; 0, 11; 0, 0], 1.0, 1.0, 0.0);
+        solver.set_periodic(0, true);
+        solver.set_periodic(2, true);
+        solver.set_scalar("temperature", 1.0);
+        let flux = 1e-3;
+        let scalar = solver.scalar_mut().unwrap();
+        scalar.set_boundary(Face::YMin, ScalarBoundary::Neumann(flux));
+        scalar.set_boundary(Face::YMax, ScalarBoundary::Dirichlet(0.0));
+        scalar.init(|_| 0.0);
+        solver.equilibrium_init();
+        solver.moments();
+        for _ in 0..4000 {
+            solver.streaming();
+            solver.apply_bcs();
+            solver.moments();
+            solver.collision();
+        }
+
+        let scalar = solver.scalar().unwrap();
+        let d = scalar.diffusivity();
+        for y in 1..=10 {
+            let expected = flux / d * (10.5 - y as f32);
+            let t = scalar.value().get(&vector![1, y, 0]);
+            assert!((t - expected).abs() < 1e-3 * expected.max(flux), "y: {}, t: {}, expected: {}", y, t, expected);
+        }
+    }
+
+    #[test]
+    fn scalar_advection() {
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 63; 0, 0; 0, 0], 1.0, 1.0, 0.0);
+        for axis in 0..3 {
+            solver.set_periodic(axis, true);
+        }
+        let u = vector![0.1, 0.0, 0.0];
+        for coord in coord_iter(solver.grid_dimensions) {
+            for (q_i, w_i) in D2Q9_W.iter().enumerate() {
+                let c_u = solver.directions[q_i].dot(&u);
+                solver.distributions.set_q(&coord, q_i as i32, equilibrium(*w_i as f32, 1.0, c_u, u.dot(&u), solver.c_sqr));
+            }
+        }
+        solver.set_scalar("concentration", 1.5);
+        solver.scalar_mut().unwrap().init(|coord| (-((coord[0] - 16) as f32).powi(2) / 8.0).exp());
+
+        let moment = |solver: &Solver<D2Q9>, order: i32| {
+            coord_iter(solver.grid_dimensions)
+                .map(|coord| solver.scalar().unwrap().value().get(&coord) * (coord[0] as f32).powi(order))
+                .sum::<f32>()
+        };
+        let mass = moment(&solver, 0);
+        let center = moment(&solver, 1) / mass;
+        solver.moments();
+        for _ in 0..200 {
+            solver.streaming();
+            solver.apply_bcs();
+            solver.moments();
+            solver.collision();
+        }
+
+        // The pulse keeps its mass and moves with the flow, lagging by u / omega
+        // as it starts from the equilibrium at rest
+        assert!((moment(&solver, 0) - mass).abs() / mass < 1e-5);
+        let moved = moment(&solver, 1) / mass - center;
+        let expected = 200.0 * u[0] - u[0] / 1.5;
+        assert!((moved - expected).abs() < 0.01, "moved: {}, expected: {}", moved, expected);
+    }
+
+    #[test]
+    fn differentially_heated_slot() {
+        // Vertical slot between a hot wall at x = 0 and a cold one at x = 11
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 11; 0, 3; 0, 0], 1.0, 1.0, 0.0);
+        solver.set_periodic(1, true);
+        solver.set_periodic(2, true);
+        solver.set_scalar("temperature", 1.0);
+        let scalar = solver.scalar_mut().unwrap();
+        scalar.set_boundary(Face::XMin, ScalarBoundary::Dirichlet(1.0));
+        scalar.set_boundary(Face::XMax, ScalarBoundary::Dirichlet(0.0));
+        scalar.init(|_| 0.5);
+        let g = 1e-3;
+        solver.set_boussinesq(Boussinesq {
+            gravity: vector![0.0, -g, 0.0],
+            expansion: 1.0,
+            reference: 0.5,
+        });
+        solver.equilibrium_init();
+        solver.moments();
+        for _ in 0..3000 {
+            solver.streaming();
+            solver.apply_bcs();
+            solver.moments();
+            solver.collision();
+        }
+
+        // Conduction across the slot, hot fluid rising along the hot wall
+        let nu = D2Q9::c_sqr() as f32 * 0.5;
+        let scale = g * 100.0 / (12.0 * nu);
+        for x in 1..=10 {
+            let xi = (x as f32 - 0.5) / 10.0;
+            let expected = scale * (xi - 3.0 * xi * xi + 2.0 * xi * xi * xi);
+            let u = solver.velocity().get(&vector![x, 1, 0]);
+            assert!((u[1] - expected).abs() < 0.01 * scale, "x: {}, u: {}, expected: {}", x, u[1], expected);
+            let t = solver.scalar().unwrap().value().get(&vector![x, 1, 0]);
+            assert!((t - (1.0 - xi)).abs() < 1e-3, "x: {}, t: {}", x, t);
+        }
+        for face in [Face::XMin, Face::XMax] {
+            let nusselt = solver.nusselt(face).unwrap();
+            assert!((nusselt - 1.0).abs() < 1e-3, "{:?}: {}", face, nusselt);
+        }
+        assert_eq!(solver.nusselt(Face::YMin), None);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_kernels_match_serial() {
+        // On one thread the slabs run in the order of the serial build
+        let run = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let mut solver = Solver::<D3Q19>::new(matrix![0, 15; 0, 9; 0, 7], 1.6, 1.0, 0.0);
+                solver.add_velocity_inlet(Face::XMin, VelocityProfile::Parabolic { peak: vector![0.05, 0.0, 0.0] });
+                solver.add_pressure_outlet(Face::XMax, 1.0);
+                solver.add_obstacle(Obstacle::Sphere {
+                    center: vector![6.0, 4.5, 3.5],
+                    radius: 2.0,
+                });
+                solver.set_body_force(BodyForce::Uniform(vector![1e-5, 0.0, 0.0]));
+                solver.set_smagorinsky(Smagorinsky::new(0.17));
+                solver.set_scalar("temperature", 1.2);
+                solver.scalar_mut().unwrap().set_boundary(Face::XMin, ScalarBoundary::Dirichlet(1.0));
+                solver.equilibrium_init();
+                solver.moments();
+                for _ in 0..20 {
+                    solver.streaming();
+                    solver.apply_bcs();
+                    solver.moments();
+                    solver.collision();
+                }
+                let scalar = solver.scalar().unwrap().distributions.buffer.clone();
+                (solver.distributions.buffer, scalar)
+            })
+        };
+        let (serial, parallel) = (run(1), run(8));
+        let bits = |values: &[f32]| values.iter().map(|q| q.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&serial.0), bits(&parallel.0));
+        assert_eq!(bits(&serial.1), bits(&parallel.1));
+    }
+
+    /// Runs the case of `setup` configured by `configure_a` and by `configure_b` for
+    /// `steps` steps each, and asserts that densities, velocities and the populations
+    /// of non solid nodes agree within `tolerance`
+    fn assert_modes_match<V: VelocitySet>(
+        setup: impl Fn() -> Solver<V>,
+        configure_a: impl Fn(&mut Solver<V>),
+        configure_b: impl Fn(&mut Solver<V>),
+        steps: usize,
+        tolerance: f32,
+    ) -> (Solver<V>, Solver<V>) {
+        let run = |configure: &dyn Fn(&mut Solver<V>)| {
+            let mut solver = setup();
+            configure(&mut solver);
+            for _ in 0..steps {
+                solver.step();
+            }
+            solver
+        };
+        let (a, b) = (run(&configure_a), run(&configure_b));
+        for coord in coord_iter(a.grid_dimensions) {
+            assert!((b.density().get(&coord) - a.density().get(&coord)).abs() < tolerance, "{:?}", coord);
+            assert!((b.velocity().get(&coord) - a.velocity().get(&coord)).amax() < tolerance, "{:?}", coord);
+            if a.node_types.get(&coord) == NodeType::Solid {
+                continue;
+            }
+            for q_i in 0..V::Q {
+                let (q, expected) = (b.population(&coord, q_i), a.population(&coord, q_i));
+                assert!((q - expected).abs() < tolerance, "{:?} {}: {} {}", coord, q_i, q, expected);
+            }
+        }
+        (a, b)
+    }
+
+    #[test]
+    fn fused_kernels_match_split() {
+        let setup = || {
+            let mut solver = Solver::<D3Q19>::new(matrix![0, 13; 0, 9; 0, 7], 1.6, 1.0, 0.0);
+            solver.add_velocity_inlet(Face::XMin, VelocityProfile::Parabolic { peak: vector![0.05, 0.0, 0.0] });
+            solver.add_pressure_outlet(Face::XMax, 1.0);
+            solver.set_moving_wall(Face::YMax, vector![0.03, 0.0, 0.01]);
+            solver.set_periodic(2, true);
+            solver.add_obstacle(Obstacle::Sphere {
+                center: vector![5.0, 4.5, 3.5],
+                radius: 2.0,
+            });
+            solver.set_body_force(BodyForce::Uniform(vector![1e-5, 0.0, 0.0]));
+            solver.set_smagorinsky(Smagorinsky::new(0.17));
+            solver.set_scalar("temperature", 1.2);
+            solver.scalar_mut().unwrap().set_boundary(Face::YMin, ScalarBoundary::Dirichlet(1.1));
+            solver.set_boussinesq(Boussinesq {
+                gravity: vector![0.0, -0.001, 0.0],
+                expansion: 0.1,
+                reference: 1.0,
+            });
+            solver.equilibrium_init();
+            solver.scalar_mut().unwrap().init(|_| 1.0);
+            solver.moments();
+            solver
+        };
+
+        let bits = |values: &[f32]| values.iter().map(|q| q.to_bits()).collect::<Vec<_>>();
+        for (propagation, layout) in [
+            (Propagation::Split, Layout::Soa),
+            (Propagation::Fused, Layout::Soa),
+            (Propagation::InPlace, Layout::Soa),
+            (Propagation::InPlace, Layout::Aos),
+        ] {
+            // Populations agree up to rounding of the moving wall term where they were
+            // streamed in place, the moments exactly
+            let configure = |solver: &mut Solver<D3Q19>| {
+                solver.set_propagation(propagation);
+                solver.set_layout(layout);
+            };
+            let (split, solver) = assert_modes_match(setup, |_| {}, configure, 12, 1e-6);
+            if propagation == Propagation::InPlace {
+                assert!(solver.distributions_buffer.buffer.is_empty());
+            }
+            assert_eq!(bits(&solver.pressure.buffer), bits(&split.pressure.buffer), "{:?}", propagation);
+            assert_eq!(solver.velocity.buffer, split.velocity.buffer, "{:?}", propagation);
+            assert_eq!(
+                bits(&solver.eddy_viscosity.as_ref().unwrap().buffer),
+                bits(&split.eddy_viscosity.as_ref().unwrap().buffer)
+            );
+            let temperature = |solver: &Solver<D3Q19>| bits(&solver.scalar().unwrap().value().buffer);
+            assert_eq!(temperature(&solver), temperature(&split));
+        }
+    }
+
+    #[test]
+    fn simd_kernels_match_scalar() {
+        let setup = || {
+            let mut solver = Solver::<D3Q27>::new(matrix![0, 12; 0, 9; 0, 6], 1.6, 1.0, 0.0);
+            solver.add_velocity_inlet(Face::XMin, VelocityProfile::Parabolic { peak: vector![0.05, 0.0, 0.0] });
+            solver.add_pressure_outlet(Face::XMax, 1.0);
+            solver.set_moving_wall(Face::YMax, vector![0.03, 0.0, 0.0]);
+            solver.add_obstacle(Obstacle::Sphere {
+                center: vector![5.0, 4.5, 3.0],
+                radius: 2.0,
+            });
+            solver.set_body_force(BodyForce::Uniform(vector![1e-5, 0.0, 0.0]));
+            // Far from equilibrium, so that the relaxation matters
+            for coord in coord_iter(solver.grid_dimensions) {
+                for (q_i, w_i) in D3Q27_W.iter().enumerate() {
+                    let phase = (coord[0] + 2 * coord[1] + 3 * coord[2] + q_i as i32) as f32;
+                    solver.distributions.set_q(&coord, q_i as i32, *w_i as f32 * (1.0 + 0.1 * phase.sin()));
+                }
+            }
+            solver.moments();
+            solver
+        };
+
+        let (_, simd) = assert_modes_match(setup, |_| {}, |solver| solver.set_simd(true), 10, 1e-6);
+        assert_eq!(simd.layout(), Layout::Soa);
+    }
+
+    #[test]
+    fn shifted_storage_matches() {
+        for (simd, propagation, cumulant) in [
+            (false, Propagation::Split, false),
+            (true, Propagation::Split, false),
+            (false, Propagation::InPlace, false),
+            (false, Propagation::Fused, true),
+        ] {
+            let setup = || {
+                let mut solver = Solver::<D3Q27>::new(matrix![0, 10; 0, 7; 0, 5], 1.6, 1.0, 0.0);
+                solver.add_velocity_inlet(Face::XMin, VelocityProfile::Parabolic { peak: vector![0.05, 0.0, 0.0] });
+                solver.add_pressure_outlet(Face::XMax, 1.0);
+                solver.set_moving_wall(Face::YMax, vector![0.03, 0.0, 0.0]);
+                solver.add_obstacle(Obstacle::Sphere {
+                    center: vector![4.0, 3.5, 2.5],
+                    radius: 1.5,
+                });
+                solver.set_body_force(BodyForce::Uniform(vector![1e-5, 0.0, 0.0]));
+                if cumulant {
+                    solver.set_collision(Cumulant::new(1.6));
+                }
+                solver.set_simd(simd);
+                solver.set_propagation(propagation);
+                solver.equilibrium_init();
+                solver.moments();
+                solver
+            };
+            let (_, shifted) = assert_modes_match(setup, |_| {}, |solver| solver.set_shifted(true), 10, 1e-5);
+            assert!(shifted.shifted());
+        }
+        // At rest the stored populations vanish
+        let mut solver = Solver::<D3Q19>::new(matrix![0, 3; 0, 3; 0, 3], 1.0, 1.0, 0.0);
+        solver.set_shifted(true);
+        solver.equilibrium_init();
+        assert!(solver.distributions.buffer.iter().all(|q| *q == 0.0));
+        solver.set_shifted(false);
+        assert_eq!(solver.population(&vector![1, 1, 1], 0), D3Q19::weights()[0] as f32);
+    }
+
+    #[test]
+    fn sparse_tiles_follow_fluid() {
+        // Fluid only in sparse pores along the bottom, many tiles there end up solid
+        let grid_dimensions = matrix![0, 63; 0, 31; 0, 31];
+        let mut solver = Solver::<D3Q19>::new(grid_dimensions, 1.2, 1.0, 0.0);
+        solver.add_obstacle(Obstacle::Block(matrix![0, 63; 6, 31; 0, 31]));
+        solver.add_obstacle(Obstacle::Porous {
+            aabb: matrix![0, 63; 0, 5; 0, 31],
+            solid_fraction: 0.999,
+            seed: 5,
+        });
+        solver.equilibrium_init();
+        solver.set_sparse(true);
+
+        let occupied: std::collections::HashSet<Coord<3>> = coord_iter(grid_dimensions)
+            .filter(|coord| solver.node_types.get(coord) != NodeType::Solid)
+            .map(|coord| coord / TILE_SIZE)
+            .collect();
+        let tiles = solver.sparse().unwrap();
+        assert_eq!(tiles.dense_tile_count(), 8 * 4 * 4);
+        assert_eq!(tiles.tile_count(), occupied.len());
+        assert!(tiles.tile_count() > 0 && tiles.tile_count() < 8 * 4);
+        assert_eq!(tiles.buffer.len(), tiles.tile_count() * 512 * D3Q19::Q);
+    }
+
+    #[test]
+    #[should_panic(expected = "before set_sparse")]
+    fn obstacle_after_sparse() {
+        // The tiles were allocated for the old fluid, refuse rather than go stale
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 15; 0, 15; 0, 0], 1.0, 1.0, 0.0);
+        solver.equilibrium_init();
+        solver.set_sparse(true);
+        solver.add_obstacle(Obstacle::Block(matrix![4, 7; 4, 7; 0, 0]));
+    }
+
+    #[test]
+    #[should_panic(expected = "set_indirect")]
+    fn periodic_after_indirect() {
+        let mut solver = Solver::<D2Q9>::new(matrix![0, 15; 0, 15; 0, 0], 1.0, 1.0, 0.0);
+        solver.equilibrium_init();
+        solver.set_indirect(true);
+        solver.set_periodic(0, true);
+    }
+
+    #[test]
+    fn sparse_storage_matches_dense() {
+        let run = |storage: fn(&mut Solver<D3Q19>)| {
+            let mut solver = Solver::<D3Q19>::new(matrix![0, 20; 0, 17; 0, 12], 1.4, 1.0, 0.0);
+            solver.set_periodic(0, true);
+            solver.set_periodic(2, true);
+            solver.set_moving_wall(Face::YMin, vector![0.02, 0.0, 0.01]);
+            // A channel along the bottom through porous media, solid above
+            solver.add_obstacle(Obstacle::Block(matrix![0, 20; 8, 17; 0, 12]));
+            solver.add_obstacle(Obstacle::Porous {
+                aabb: matrix![4, 16; 0, 7; 0, 12],
+                solid_fraction: 0.4,
+                seed: 3,
+            });
+            solver.set_body_force(BodyForce::Uniform(vector![2e-5, 0.0, 0.0]));
+            solver.equilibrium_init();
+            solver.moments();
+            solver.set_propagation(Propagation::Fused);
+            storage(&mut solver);
+            for _ in 0..15 {
+                solver.step();
+            }
+            solver
+        };
+
+        let mut dense = run(|_| {});
+        let mut sparse = run(|solver| solver.set_sparse(true));
+        let mut indirect = run(|solver| solver.set_indirect(true));
+        let tiles = sparse.sparse().unwrap();
+        assert_eq!((tiles.tile_count(), tiles.dense_tile_count()), (6, 18));
+        let fluid = box_buffer_size(&dense.grid_dimensions) - dense.node_types.count(NodeType::Solid);
+        assert_eq!(indirect.indirect().unwrap().nodes().len(), fluid);
+        assert!(indirect.distributions.buffer.is_empty());
+
+        let bits = |values: &[f32]| values.iter().map(|q| q.to_bits()).collect::<Vec<_>>();
+        for solver in [&sparse, &indirect] {
+            assert_eq!(bits(&solver.pressure.buffer), bits(&dense.pressure.buffer));
+            assert_eq!(solver.velocity.buffer, dense.velocity.buffer);
+        }
+        sparse.set_sparse(false);
+        indirect.set_indirect(false);
+        for solver in [&mut dense, &mut sparse, &mut indirect] {
+            solver.step();
+        }
+        for coord in coord_iter(dense.grid_dimensions) {
+            if dense.node_types.get(&coord) == NodeType::Solid {
+                continue;
+            }
+            for q_i in 0..D3Q19::Q {
+                let expected = dense.population(&coord, q_i).to_bits();
+                assert_eq!(sparse.population(&coord, q_i).to_bits(), expected);
+                assert_eq!(indirect.population(&coord, q_i).to_bits(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn geometry_report() {
+        let mut solver = Solver::<D3Q19>::new(matrix![0, 9; 0, 9; 0, 9], 1.0, 1.0, 0.0);
+        for axis in 0..3 {
+            solver.set_periodic(axis, true);
+        }
+        let mut mesh = TriangleMesh::parse_obj(
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1\n\
+             f 1 4 3 2\nf 5 6 7 8\nf 1 2 6 5\nf 4 8 7 3\nf 1 5 8 4\nf 2 3 7 6\n",
+        )
+        .unwrap();
+        mesh.transform(2.0, Vec3::repeat(3.5));
+        let report = solver.add_geometry(&mesh);
+
+        // A 2^3 block, wetted by its 6 * 4 face, 12 * 2 edge and no corner neighbors in D3Q19
+        assert_eq!(report.solid_nodes, 8);
+        assert_eq!(report.fluid_nodes, 1000 - 8);
+        assert_eq!(report.wetted_nodes, 24 + 24);
+    }
+}
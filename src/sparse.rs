@@ -0,0 +1,203 @@
+use crate::*;
+
+/// Nodes along each axis of a `BlockSparse` tile
+pub const TILE_SIZE: i32 = 8;
+
+const TILE_NODES: usize = (TILE_SIZE * TILE_SIZE * TILE_SIZE) as usize;
+
+/// Marks a tile that holds no populations
+const UNALLOCATED: u32 = u32::MAX;
+
+/// Block sparse populations, see `Solver::set_sparse`. The grid is cut into tiles
+/// of `TILE_SIZE`^3 nodes and only tiles with a non solid node are allocated, the
+/// populations of a node being contiguous within its tile. Streaming crosses into
+/// the 26 surrounding tiles through a neighbor table, which wraps around every
+/// axis so that periodic links need no special case.
+#[derive(Clone)]
+pub struct BlockSparse<T = f32> {
+    grid_dimensions: AABB<3>,
+    q_count: usize,
+    /// Tiles per axis, as a box from zero
+    tile_grid: AABB<3>,
+    /// Allocated tile of each position in `tile_grid`, or `UNALLOCATED`
+    tile_index: Vec<u32>,
+    /// Lowest node of each allocated tile
+    origins: Vec<Coord<3>>,
+    /// Allocated tile one step along each of the 27 offsets, see `step_index`
+    neighbors: Vec<[u32; 27]>,
+    pub buffer: Vec<T>,
+}
+
+/// Index into the neighbor table of a tile step of -1, 0 or 1 per axis
+fn step_index(step: &Coord<3>) -> usize {
+    ((step[0] + 1) * 9 + (step[1] + 1) * 3 + step[2] + 1) as usize
+}
+
+impl<T: NumTrait> BlockSparse<T> {
+    /// Allocate the tiles of `node_types` with a fluid or boundary node, each node
+    /// with `q_count` populations
+    pub fn new(node_types: &NodeTypeArray, q_count: usize) -> Self {
+        let grid_dimensions = *node_types.dimensions();
+        let mut tile_grid = AABB::zeros();
+        for d in 0..3 {
+            let extent = grid_dimensions[(d, 1)] - grid_dimensions[(d, 0)] + 1;
+            tile_grid[(d, 1)] = (extent + TILE_SIZE - 1) / TILE_SIZE - 1;
+        }
+        let mut allocated = vec![false; box_buffer_size(&tile_grid)];
+        for coord in coord_iter(grid_dimensions) {
+            if node_types.get(&coord) != NodeType::Solid {
+                let tile = (coord - grid_dimensions.column(0)) / TILE_SIZE;
+                allocated[coord_to_linear_in_box(&tile, &tile_grid)] = true;
+            }
+        }
+
+        let mut tile_index = vec![UNALLOCATED; allocated.len()];
+        let mut origins = Vec::new();
+        for (position, _) in allocated.iter().enumerate().filter(|(_, allocated)| **allocated) {
+            tile_index[position] = origins.len() as u32;
+            let tile = linear_to_coord_in_box(position, &tile_grid);
+            origins.push(grid_dimensions.column(0) + tile * TILE_SIZE);
+        }
+
+        let steps = AABB::from_columns(&[Coord::repeat(-1), Coord::repeat(1)]);
+        let neighbors = origins
+            .iter()
+            .map(|origin| {
+                let tile = (origin - grid_dimensions.column(0)) / TILE_SIZE;
+                let mut row = [UNALLOCATED; 27];
+                for step in coord_iter(steps) {
+                    let neighbor = periodic_wrap(&tile_grid, &(tile + step), &[true; 3]);
+                    row[step_index(&step)] = tile_index[coord_to_linear_in_box(&neighbor, &tile_grid)];
+                }
+                row
+            })
+            .collect();
+
+        BlockSparse {
+            grid_dimensions,
+            q_count,
+            tile_grid,
+            tile_index,
+            buffer: vec![T::zero(); origins.len() * TILE_NODES * q_count],
+            origins,
+            neighbors,
+        }
+    }
+
+    pub fn grid_dimensions(&self) -> &AABB<3> {
+        &self.grid_dimensions
+    }
+
+    pub fn q_count(&self) -> usize {
+        self.q_count
+    }
+
+    /// Allocated tiles
+    pub fn tile_count(&self) -> usize {
+        self.origins.len()
+    }
+
+    /// Tiles of a dense grid of the same size
+    pub fn dense_tile_count(&self) -> usize {
+        self.tile_index.len()
+    }
+
+    /// Nodes of tile `tile` within the grid, the last tile of an axis may be partial
+    pub fn tile_aabb(&self, tile: usize) -> AABB<3> {
+        let origin = self.origins[tile];
+        let end = (origin + Coord::repeat(TILE_SIZE - 1)).inf(&self.grid_dimensions.column(1).into_owned());
+        AABB::from_columns(&[origin, end])
+    }
+
+    /// Allocated tile holding `coord`
+    pub fn tile(&self, coord: &Coord<3>) -> Option<usize> {
+        let tile = (coord - self.grid_dimensions.column(0)) / TILE_SIZE;
+        match self.tile_index[coord_to_linear_in_box(&tile, &self.tile_grid)] {
+            UNALLOCATED => None,
+            index => Some(index as usize),
+        }
+    }
+
+    /// Index into `buffer` of population `q` of `coord`, which lies in `tile`
+    pub fn index(&self, tile: usize, coord: &Coord<3>, q: usize) -> usize {
+        let local = coord - self.origins[tile];
+        debug_assert!(local.iter().all(|l| (0..TILE_SIZE).contains(l)));
+        let node = ((local[0] * TILE_SIZE + local[1]) * TILE_SIZE + local[2]) as usize;
+        (tile * TILE_NODES + node) * self.q_count + q
+    }
+
+    /// Index of population `q` of `neighbor`, the node `coord + offset` of `tile`
+    /// after periodic wrapping. The tile of `neighbor` comes from the neighbor
+    /// table and must be allocated.
+    pub fn neighbor_index(
+        &self,
+        tile: usize,
+        coord: &Coord<3>,
+        offset: &Coord<3>,
+        neighbor: &Coord<3>,
+        q: usize,
+    ) -> usize {
+        let aabb = self.tile_aabb(tile);
+        let step = Coord::from_fn(|d, _| {
+            let target = coord[d] + offset[d];
+            (target > aabb[(d, 1)]) as i32 - (target < aabb[(d, 0)]) as i32
+        });
+        let neighbor_tile = self.neighbors[tile][step_index(&step)];
+        debug_assert!(neighbor_tile != UNALLOCATED);
+        self.index(neighbor_tile as usize, neighbor, q)
+    }
+
+    /// Nodes of unallocated tiles are solid and read as zero
+    pub fn get_q(&self, coord: &Coord<3>, q: i32) -> T {
+        match self.tile(coord) {
+            Some(tile) => self.buffer[self.index(tile, coord, q as usize)],
+            None => T::zero(),
+        }
+    }
+
+    /// Writes to nodes of unallocated tiles are dropped
+    pub fn set_q(&mut self, coord: &Coord<3>, q: i32, value: T) {
+        if let Some(tile) = self.tile(coord) {
+            let index = self.index(tile, coord, q as usize);
+            self.buffer[index] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::{matrix, vector};
+
+    #[test]
+    fn tiles_and_neighbors() {
+        // 3 x 2 x 1 tiles, the last ones along x partial
+        let grid_dimensions = matrix![0, 19; 0, 15; 0, 7];
+        let mut node_types = NodeTypeArray::new(grid_dimensions);
+        for coord in coord_iter(grid_dimensions) {
+            if coord[1] >= 8 && coord != vector![17, 12, 3] {
+                node_types.set(&coord, NodeType::Solid);
+            }
+        }
+        let mut sparse = BlockSparse::new(&node_types, 2);
+        assert_eq!((sparse.tile_count(), sparse.dense_tile_count()), (4, 6));
+        assert_eq!(sparse.buffer.len(), 4 * 512 * 2);
+        assert_eq!(sparse.tile_aabb(3), matrix![16, 19; 8, 15; 0, 7]);
+        assert_eq!(sparse.tile(&vector![3, 12, 0]), None);
+
+        for (i, coord) in coord_iter(grid_dimensions).enumerate() {
+            sparse.set_q(&coord, 1, i as f32);
+        }
+        let linear = coord_to_linear_in_box(&vector![17, 12, 3], &grid_dimensions);
+        assert_eq!(sparse.get_q(&vector![17, 12, 3], 1), linear as f32);
+        assert_eq!(sparse.get_q(&vector![3, 12, 0], 1), 0.0);
+
+        // Into the next tile, and around the periodic x axis from the partial tile
+        let tile = sparse.tile(&vector![7, 3, 5]).unwrap();
+        let index = sparse.neighbor_index(tile, &vector![7, 3, 5], &vector![1, 1, 0], &vector![8, 4, 5], 1);
+        assert_eq!(index, sparse.index(sparse.tile(&vector![8, 4, 5]).unwrap(), &vector![8, 4, 5], 1));
+        let tile = sparse.tile(&vector![19, 7, 0]).unwrap();
+        let index = sparse.neighbor_index(tile, &vector![19, 7, 0], &vector![1, 0, -1], &vector![0, 7, 7], 0);
+        assert_eq!(index, sparse.index(0, &vector![0, 7, 7], 0));
+    }
+}
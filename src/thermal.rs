@@ -0,0 +1,91 @@
+use crate::*;
+
+/// Boussinesq buoyancy, see `Solver::set_boussinesq`. The density only changes
+/// with temperature through the force `-rho beta (T - T_ref) g`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Boussinesq<T = f32> {
+    /// Gravitational acceleration, pointing down
+    pub gravity: Vec3<T>,
+
+    /// Thermal expansion coefficient `beta`
+    pub expansion: T,
+
+    /// Temperature at which there is no buoyancy
+    pub reference: T,
+}
+
+impl<T: NumTrait> Boussinesq<T> {
+    pub fn force(&self, rho: T, temperature: T) -> Vec3<T> {
+        self.gravity * (-rho * self.expansion * (temperature - self.reference))
+    }
+
+    /// `|g| beta delta_t height^3 / (nu kappa)`
+    pub fn rayleigh(&self, delta_t: T, height: T, viscosity: T, diffusivity: T) -> T {
+        self.gravity.norm() * self.expansion * delta_t * height.powi(3) / (viscosity * diffusivity)
+    }
+}
+
+/// Nusselt number at `face` of the scalar field, its wall heat flux over the
+/// conductive one. Both `face` and the opposite face must have a fixed temperature.
+///
+/// The wall gradient comes from a quadratic through the wall temperature and the
+/// first two fluid nodes, averaged over the face.
+pub fn nusselt<T: NumTrait>(scalar: &ScalarField<T>, node_types: &NodeTypeArray, face: Face) -> Option<T> {
+    let opposite = Face::all()
+        .into_iter()
+        .find(|f| f.axis() == face.axis() && f.is_max() != face.is_max())?;
+    let (t_wall, t_opposite) = match (scalar.boundary(face), scalar.boundary(opposite)) {
+        (ScalarBoundary::Dirichlet(t_wall), ScalarBoundary::Dirichlet(t_opposite)) => (t_wall, t_opposite),
+        _ => return None,
+    };
+
+    let grid_dimensions = scalar.value().dimensions();
+    let inward = -face.normal();
+    let wall_offset = |face: Face| {
+        let layer = face.aabb(grid_dimensions);
+        coord_iter(layer).all(|coord| node_types.get(&coord) == NodeType::Solid) as i32
+    };
+    // Halfway walls, behind a layer of wall nodes or outside the domain
+    let axis = face.axis();
+    let offset = wall_offset(face);
+    let length = T::of((grid_dimensions[(axis, 1)] - grid_dimensions[(axis, 0)] + 1 - offset - wall_offset(opposite)) as f64);
+
+    let mut gradient = T::zero();
+    let mut count = 0;
+    for coord in coord_iter(face.aabb(grid_dimensions)) {
+        let first = coord + inward * offset;
+        let second = first + inward;
+        if !box_contains_coord(grid_dimensions, &second)
+            || node_types.get(&first) == NodeType::Solid
+            || node_types.get(&second) == NodeType::Solid
+        {
+            continue;
+        }
+        let (t_1, t_2) = (scalar.value().get(&first), scalar.value().get(&second));
+        gradient += (T::of(9.0) * t_1 - t_2 - T::of(8.0) * t_wall) / T::of(3.0);
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+    Some(-gradient / T::of(count as f64) * length / (t_wall - t_opposite))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::vector;
+
+    #[test]
+    fn warm_fluid_rises() {
+        let model = Boussinesq {
+            gravity: vector![0.0_f32, 0.0, -0.01],
+            expansion: 0.5,
+            reference: 1.0,
+        };
+        assert!(model.force(1.0, 1.2)[2] > 0.0);
+        assert!(model.force(1.0, 0.8)[2] < 0.0);
+        assert_eq!(model.force(1.0, 1.0), Vec3::zero());
+        assert!((model.rayleigh(1.0, 10.0, 0.1, 0.1) - 500.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::*;
+use nalgebra::Matrix3;
+
+/// Non-equilibrium momentum flux `sum_i c_i c_i (f_i - f_eq_i)` of one node
+pub fn non_equilibrium_stress<T: NumTrait>(directions: &[Vec3<T>], f: &[T], f_eq: &[T]) -> Matrix3<T> {
+    let mut result = Matrix3::zeros();
+    for (c, (q, q_eq)) in directions.iter().zip(f.iter().zip(f_eq)) {
+        result += c * c.transpose() * (*q - *q_eq);
+    }
+    result
+}
+
+/// Smagorinsky subgrid model, see `Solver::set_smagorinsky`.
+///
+/// The eddy viscosity `(C_s Delta)^2 |S|` is added to the molecular one with the
+/// filter width `Delta` one node spacing. The strain rate `|S|` follows from the
+/// non-equilibrium stress, which itself depends on the total relaxation time, so
+/// the relaxation time solves a quadratic.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Smagorinsky<T = f32> {
+    /// `C_s`, usually between 0.1 and 0.2
+    pub constant: T,
+}
+
+impl<T: NumTrait> Smagorinsky<T> {
+    pub fn new(constant: T) -> Self {
+        Smagorinsky { constant }
+    }
+
+    /// Total relaxation time of a node with molecular relaxation time `tau`,
+    /// `stress` from `non_equilibrium_stress`
+    pub fn relaxation_time(&self, tau: T, stress: &Matrix3<T>, rho: T, c_sqr: T) -> T {
+        let q = stress.norm();
+        let c = self.constant * self.constant;
+        let scale = T::of(2.0 * std::f64::consts::SQRT_2);
+        T::of(0.5) * (tau + (tau * tau + scale * c * q / (rho * c_sqr * c_sqr)).sqrt())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use nalgebra::matrix;
+
+    #[test]
+    fn eddy_viscosity_matches_strain_rate() {
+        let model = Smagorinsky::new(0.17);
+        let c_sqr = 1.0_f32 / 3.0;
+        let (tau_0, rho) = (0.52, 1.1);
+        let stress = matrix![1e-3, 2e-4, 0.0; 2e-4, -5e-4, 1e-4; 0.0, 1e-4, -5e-4];
+        let tau = model.relaxation_time(tau_0, &stress, rho, c_sqr);
+
+        // |S| = sqrt(2 S : S) with S = -stress / (2 rho c_s^2 tau)
+        let strain = stress / (-2.0 * rho * c_sqr * tau);
+        let strain_rate = (2.0 * strain.dot(&strain)).sqrt();
+        let eddy_viscosity = c_sqr * (tau - tau_0);
+        assert!((eddy_viscosity - 0.17 * 0.17 * strain_rate).abs() < 1e-6);
+
+        assert_eq!(Smagorinsky::new(0.0).relaxation_time(tau_0, &stress, rho, c_sqr), tau_0);
+    }
+}